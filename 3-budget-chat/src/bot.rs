@@ -0,0 +1,156 @@
+//! Bots, automated users living inside the server.
+//!
+//! A bot is driven by a [`Session`] like any connected user, so it holds a reserved name, shows up
+//! in room listings, and can use every command. Bots are enabled at startup with
+//! `--bots=<name>,...`, see [`from_config`] for the available ones.
+
+mod echo;
+mod reminder;
+mod tickets;
+
+use anyhow::{anyhow, bail};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tracing::{error, info};
+
+use crate::{admit, config::Config, session::Session, Msg, MsgKind, State};
+
+pub trait Bot: Send + 'static {
+    /// Name the bot joins with, it has to be a valid user name.
+    fn name(&self) -> &'static str;
+
+    /// Called once the bot joined the default room. Bots posting on their own, instead of only
+    /// answering, start doing so here.
+    fn start(&mut self, _ctx: &BotContext) {}
+
+    /// Called with every message in the bot's room, except its own, and every private message
+    /// sent to it.
+    fn on_msg(&mut self, _ctx: &BotContext, _msg: &Msg) {}
+}
+
+/// Lets a bot post, also from tasks it spawned.
+#[derive(Clone)]
+pub struct BotContext {
+    tx: mpsc::UnboundedSender<Post>,
+}
+
+enum Post {
+    /// Handled like a line sent by a user, so it can be a command.
+    Line(String),
+    /// Said in the bot's room, even if it starts with `/`.
+    Say(String),
+}
+
+impl BotContext {
+    /// Handle `line` as if the bot sent it: commands start with `/`, anything else is said in the
+    /// bot's room. Only for lines the bot built itself, text from users goes through [`Self::say`].
+    pub fn send(&self, line: impl Into<String>) {
+        self.post(Post::Line(line.into()));
+    }
+
+    /// Say `text` in the bot's room, never running it as a command.
+    pub fn say(&self, text: impl Into<String>) {
+        self.post(Post::Say(text.into()));
+    }
+
+    /// Reply to `msg` where it came from: privately if it was a private message.
+    pub fn reply(&self, msg: &Msg, text: &str) {
+        match msg.kind {
+            // everything after the name is the text, whatever it starts with
+            MsgKind::Direct { .. } => self.send(format!("/msg {} {text}", msg.from)),
+            _ => self.say(text),
+        }
+    }
+
+    fn post(&self, post: Post) {
+        // only fails once the bot is gone
+        let _ = self.tx.send(post);
+    }
+}
+
+/// The text of a chat or private message.
+pub fn text(msg: &Msg) -> Option<&str> {
+    match &msg.kind {
+        MsgKind::Chat(text) | MsgKind::Direct { text, .. } => Some(text),
+        _ => None,
+    }
+}
+
+/// Every bot enabled with `--bots=<name>,...`:
+///
+/// - `echo`: repeats `!echo <text>`
+/// - `reminder`: answers `!remind <seconds> <text>` with `text` after `seconds`, with a few
+///   reminders pending per user at most
+/// - `tickets`: posts the tickets of speed-daemon, by following the file it writes with
+///   `--export=jsonl:<path>`, given as `--ticket-feed=<path>`
+pub fn from_config(config: &Config) -> anyhow::Result<Vec<Box<dyn Bot>>> {
+    let mut bots: Vec<Box<dyn Bot>> = Vec::new();
+
+    for name in &config.bots {
+        match name.as_str() {
+            "echo" => bots.push(Box::new(echo::Echo)),
+            "reminder" => bots.push(Box::<reminder::Reminder>::default()),
+            "tickets" => {
+                let path = config
+                    .ticket_feed
+                    .clone()
+                    .ok_or_else(|| anyhow!("the tickets bot needs --ticket-feed=<path>"))?;
+                bots.push(Box::new(tickets::Tickets::new(path)));
+            }
+            name => bail!("unknown bot {name}"),
+        }
+    }
+
+    Ok(bots)
+}
+
+/// Reserve the name of `bot` and let it join, then keep it running in the background.
+pub async fn start(state: State, mut bot: Box<dyn Bot>) -> anyhow::Result<()> {
+    let name = bot.name().to_string();
+    let lang = state.config.lang;
+    let mut session = admit(state, name.clone(), None)
+        .await
+        .map_err(|rejection| anyhow!("bot {name}: {}", lang.rejection(rejection).trim()))?;
+
+    let (tx, posts) = mpsc::unbounded_channel();
+    let ctx = BotContext { tx };
+    bot.start(&ctx);
+    info!("started bot {name}");
+
+    tokio::spawn(async move {
+        if let Err(e) = run(&mut session, bot.as_mut(), &ctx, posts).await {
+            error!("bot {name} failed: {e}");
+        }
+        session.finish().await;
+        info!("stopped bot {name}");
+    });
+
+    Ok(())
+}
+
+async fn run(
+    session: &mut Session,
+    bot: &mut dyn Bot,
+    ctx: &BotContext,
+    mut posts: mpsc::UnboundedReceiver<Post>,
+) -> anyhow::Result<()> {
+    loop {
+        tokio::select! {
+            res_msg = session.joined.rx.recv() => match res_msg {
+                Ok(msg) if msg.from != session.name => bot.on_msg(ctx, &msg),
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            Some(msg) = session.direct_rx.recv() => match msg.kind {
+                MsgKind::Kick => return Ok(()),
+                _ => bot.on_msg(ctx, &msg),
+            },
+            Some(post) = posts.recv() => match post {
+                Post::Line(line) => session.dispatch(&line).await?,
+                Post::Say(text) => session.say(&text).await?,
+            },
+        }
+
+        // nobody reads the replies meant for the bot
+        session.take_output();
+    }
+}
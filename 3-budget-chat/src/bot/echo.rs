@@ -0,0 +1,19 @@
+use crate::{
+    bot::{self, Bot, BotContext},
+    Msg,
+};
+
+/// Repeats `!echo <text>`, privately when asked privately.
+pub struct Echo;
+
+impl Bot for Echo {
+    fn name(&self) -> &'static str {
+        "echobot"
+    }
+
+    fn on_msg(&mut self, ctx: &BotContext, msg: &Msg) {
+        if let Some(text) = bot::text(msg).and_then(|text| text.strip_prefix("!echo ")) {
+            ctx.reply(msg, text);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    bot::{self, Bot, BotContext},
+    Msg, UserName,
+};
+
+/// Longest accepted reminder, a day.
+const MAX_SECS: u64 = 24 * 60 * 60;
+
+/// Most reminders a single user can have pending.
+const MAX_PER_USER: usize = 5;
+
+/// Most reminders pending at once, from all users together.
+const MAX_PENDING: usize = 1000;
+
+/// Answers `!remind <seconds> <text>` with `text` once `seconds` passed.
+#[derive(Default)]
+pub struct Reminder {
+    /// Number of pending reminders by user, shared with the tasks waiting to send them.
+    pending: Arc<Mutex<HashMap<UserName, usize>>>,
+}
+
+impl Reminder {
+    /// Count a new reminder of `user`, or return `false` if there are too many pending already.
+    fn reserve(&self, user: &UserName) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let total: usize = pending.values().sum();
+        let of_user = pending.get(user).copied().unwrap_or(0);
+        if of_user >= MAX_PER_USER || total >= MAX_PENDING {
+            return false;
+        }
+        pending.insert(user.clone(), of_user + 1);
+        true
+    }
+}
+
+fn release(pending: &Mutex<HashMap<UserName, usize>>, user: &UserName) {
+    let mut pending = pending.lock().unwrap();
+    if let Some(of_user) = pending.get_mut(user) {
+        *of_user -= 1;
+        if *of_user == 0 {
+            pending.remove(user);
+        }
+    }
+}
+
+impl Bot for Reminder {
+    fn name(&self) -> &'static str {
+        "remindbot"
+    }
+
+    fn on_msg(&mut self, ctx: &BotContext, msg: &Msg) {
+        let Some(arg) = bot::text(msg).and_then(|text| text.strip_prefix("!remind ")) else {
+            return;
+        };
+
+        let parsed = arg
+            .split_once(' ')
+            .and_then(|(secs, text)| Some((secs.parse::<u64>().ok()?, text.trim())));
+        let Some((secs, text)) = parsed.filter(|(secs, _)| *secs <= MAX_SECS) else {
+            ctx.reply(
+                msg,
+                &format!("usage: !remind <seconds up to {MAX_SECS}> <text>"),
+            );
+            return;
+        };
+
+        if !self.reserve(&msg.from) {
+            ctx.reply(
+                msg,
+                &format!("{}: too many pending reminders, try again later", msg.from),
+            );
+            return;
+        }
+        ctx.reply(
+            msg,
+            &format!("{}: reminding you in {secs} seconds", msg.from),
+        );
+
+        let ctx = ctx.clone();
+        let msg = msg.clone();
+        let pending = self.pending.clone();
+        let reminder = format!("{}: reminder: {text}", msg.from);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            ctx.reply(&msg, &reminder);
+            release(&pending, &msg.from);
+        });
+    }
+}
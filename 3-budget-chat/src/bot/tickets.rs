@@ -0,0 +1,113 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::bot::{Bot, BotContext};
+
+/// How often the feed is checked for new tickets.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The fields used from a record of speed-daemon's `--export=jsonl:<path>`.
+#[derive(Debug, Deserialize)]
+struct TicketRecord {
+    id: u32,
+    plate: String,
+    road: u16,
+    speed: f64,
+    status: String,
+}
+
+/// Posts every ticket written to the feed after the bot started.
+pub struct Tickets {
+    path: PathBuf,
+}
+
+impl Tickets {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Bot for Tickets {
+    fn name(&self) -> &'static str {
+        "ticketbot"
+    }
+
+    fn start(&mut self, ctx: &BotContext) {
+        let ctx = ctx.clone();
+        let path = self.path.clone();
+        tokio::spawn(async move {
+            follow(path, ctx).await;
+        });
+    }
+}
+
+/// Like `tail -f`, posting every complete line appended to the file at `path`.
+async fn follow(path: PathBuf, ctx: BotContext) {
+    // only tickets issued from now on are news
+    let len = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || std::fs::metadata(path).map_or(0, |meta| meta.len()))
+    };
+    let mut pos = len.await.unwrap_or(0);
+    let mut partial = String::new();
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let appended = match read_appended(path.clone(), pos).await {
+            Ok((appended, new_pos)) => {
+                pos = new_pos;
+                appended
+            }
+            Err(e) => {
+                warn!("failed to read ticket feed {}: {e}", path.display());
+                continue;
+            }
+        };
+        partial.push_str(&appended);
+
+        while let Some((line, rest)) = partial.split_once('\n') {
+            match serde_json::from_str::<TicketRecord>(line) {
+                Ok(ticket) => ctx.say(format!(
+                    "ticket {} for {} on road {}, {:.2} mph, {}",
+                    ticket.id, ticket.plate, ticket.road, ticket.speed, ticket.status
+                )),
+                Err(e) => warn!("invalid line in ticket feed: {e}"),
+            }
+            partial = rest.to_string();
+        }
+    }
+}
+
+/// [`read_from`] on the blocking pool, so polling the file never stalls other connections.
+async fn read_appended(path: PathBuf, pos: u64) -> anyhow::Result<(String, u64)> {
+    tokio::task::spawn_blocking(move || read_from(&path, pos)).await?
+}
+
+/// Everything written to the file after `pos`, starting over if the file was truncated, with the
+/// position to read from next time.
+fn read_from(path: &Path, mut pos: u64) -> anyhow::Result<(String, u64)> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        // not written yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((String::new(), pos)),
+        Err(e) => return Err(e.into()),
+    };
+
+    if file.metadata()?.len() < pos {
+        pos = 0;
+    }
+    file.seek(SeekFrom::Start(pos))?;
+
+    let mut appended = String::new();
+    pos += file.read_to_string(&mut appended)? as u64;
+    Ok((appended, pos))
+}
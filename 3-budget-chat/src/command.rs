@@ -0,0 +1,477 @@
+//! Lines starting with `/` are commands instead of chat messages.
+//!
+//! New commands only need a handler and a call to [`Commands::register`] in
+//! [`Commands::builtin`], the connection loop does not know about any of them.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use futures::future::BoxFuture;
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::{
+    create_current_users_message, create_rooms_message,
+    federation::Event,
+    moderation::Target,
+    room::{is_valid_room_name, RoomName, DEFAULT_ROOM},
+    session::Session,
+    text::{self, Rejection},
+    transcript::{self, Query},
+    Msg, MsgKind, UserName,
+};
+
+pub type Handler = for<'a> fn(&'a mut Session, &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+
+pub struct Command {
+    pub usage: &'static str,
+    pub help: &'static str,
+    handler: Handler,
+}
+
+#[derive(Default)]
+pub struct Commands(BTreeMap<&'static str, Command>);
+
+impl Commands {
+    pub fn builtin() -> Self {
+        let mut commands = Self::default();
+        commands.register("join", "<room>", "move to another room", join);
+        commands.register("leave", "", "go back to the lobby", leave);
+        commands.register("rooms", "", "list all rooms", rooms);
+        commands.register("msg", "<user> <text>", "send a private message", msg);
+        commands.register("who", "", "list users in this room", who);
+        commands.register("nick", "<name>", "change your name", nick);
+        commands.register("history", "[n]", "show recent messages", history);
+        commands.register("search", "<term>", "search the transcript", search);
+        commands.register("oper", "<password>", "become an operator", oper);
+        commands.register("kick", "<user>", "disconnect a user (operators)", kick);
+        commands.register("ban", "<user|ip>", "ban a name or address (operators)", ban);
+        commands.register("unban", "<user|ip>", "lift a ban (operators)", unban);
+        commands.register(
+            "mute",
+            "<user>",
+            "stop a user from talking (operators)",
+            mute,
+        );
+        commands.register(
+            "unmute",
+            "<user>",
+            "let a muted user talk again (operators)",
+            unmute,
+        );
+        commands.register("help", "", "list all commands", help);
+        commands
+    }
+
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        usage: &'static str,
+        help: &'static str,
+        handler: Handler,
+    ) {
+        self.0.insert(
+            name,
+            Command {
+                usage,
+                help,
+                handler,
+            },
+        );
+    }
+
+    /// Run `line`, which is everything after the leading `/`.
+    pub async fn run(&self, session: &mut Session, line: &str) -> anyhow::Result<()> {
+        let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+
+        match self.0.get(name) {
+            Some(command) => (command.handler)(session, arg.trim()).await,
+            None => {
+                session.reply(format!("* unknown command /{name}, try /help\n"));
+                Ok(())
+            }
+        }
+    }
+}
+
+fn join<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        if !is_valid_room_name(arg) {
+            session.reply("* room names must be 1 to 16 alphanumeric characters\n");
+            return Ok(());
+        }
+        if session.room.as_str() == arg {
+            session.reply("* you are already in this room\n");
+            return Ok(());
+        }
+
+        session.move_to(RoomName::new(arg.to_string())).await;
+        Ok(())
+    })
+}
+
+fn leave<'a>(session: &'a mut Session, _: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        if session.room.as_str() == DEFAULT_ROOM {
+            session.reply("* you are already in the lobby\n");
+            return Ok(());
+        }
+
+        session
+            .move_to(RoomName::new(DEFAULT_ROOM.to_string()))
+            .await;
+        Ok(())
+    })
+}
+
+fn rooms<'a>(session: &'a mut Session, _: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        let rooms = session.state.rooms.list().await;
+        session.reply(create_rooms_message(&rooms));
+        Ok(())
+    })
+}
+
+fn msg<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        let Some((to, text)) = arg.split_once(' ') else {
+            session.reply("* usage: /msg <user> <text>\n");
+            return Ok(());
+        };
+        let text = text::sanitize(text.trim());
+
+        if session.check_muted().await {
+            return Ok(());
+        }
+
+        let Some(tx) = session
+            .state
+            .users
+            .read()
+            .await
+            .get(&to.to_string())
+            .map(|user| user.tx.clone())
+        else {
+            session.reply(format!("* no user named {to}\n"));
+            return Ok(());
+        };
+
+        let content = Arc::new(format!("[{} -> {to}] {text}\n", session.name));
+        let msg = Msg {
+            from: session.name.clone(),
+            peer: None,
+            content: content.clone(),
+            kind: MsgKind::Direct {
+                to: Arc::new(to.to_string()),
+                text: Arc::new(text.into_owned()),
+            },
+        };
+
+        match tx.try_send(msg) {
+            Ok(()) => session.reply(content.as_str()),
+            Err(TrySendError::Full(_)) => {
+                session.reply(format!("* {to} is not keeping up, message not delivered\n"))
+            }
+            Err(TrySendError::Closed(_)) => session.reply(format!("* no user named {to}\n")),
+        }
+        Ok(())
+    })
+}
+
+fn who<'a>(session: &'a mut Session, _: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        let mut users = session.state.rooms.users(&session.room).await;
+        users.retain(|user| *user != session.name);
+        session.reply(create_current_users_message(&users));
+        Ok(())
+    })
+}
+
+fn nick<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        let (policy, lang) = (session.state.config.names, session.state.config.lang);
+        let name = match policy.validate(arg) {
+            Ok(name) => name,
+            Err(rejection) => {
+                session.reply(lang.rejection(rejection));
+                return Ok(());
+            }
+        };
+        if session.state.moderation.is_banned_name(policy, &name).await {
+            session.reply(lang.rejection(Rejection::Banned));
+            return Ok(());
+        }
+        // a new name would escape the mute
+        if session.check_muted().await {
+            return Ok(());
+        }
+
+        let new_name = Arc::new(name);
+
+        {
+            let mut lock = session.state.users.write().await;
+            // looking like your own name is fine
+            let others = lock
+                .keys()
+                .filter(|user| **user != session.name)
+                .map(|user| user.as_str());
+            if policy.is_taken(&new_name, others)
+                || session.state.federation.is_remote(policy, &new_name).await
+            {
+                drop(lock);
+                session.reply(lang.rejection(Rejection::Duplicate));
+                return Ok(());
+            }
+            if let Some(tx) = lock.remove(&session.name) {
+                lock.insert(new_name.clone(), tx);
+            }
+        }
+
+        let old_name = std::mem::replace(&mut session.name, new_name.clone());
+        session
+            .state
+            .rooms
+            .rename(&session.room, &old_name, &new_name)
+            .await;
+        session.state.federation.publish(Event::Rename {
+            room: session.room.clone(),
+            old: old_name,
+            new: new_name.clone(),
+        });
+
+        session.reply(format!("* you are now known as {new_name}\n"));
+        Ok(())
+    })
+}
+
+fn history<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        let n = match arg {
+            "" => usize::MAX,
+            arg => match arg.parse() {
+                Ok(n) => n,
+                Err(_) => {
+                    session.reply("* usage: /history [n]\n");
+                    return Ok(());
+                }
+            },
+        };
+
+        let messages = session.state.rooms.history(&session.room, n).await;
+        if messages.is_empty() {
+            session.reply("* no messages yet\n");
+        }
+        for content in messages {
+            session.reply(content.as_str());
+        }
+        Ok(())
+    })
+}
+
+/// Most matches shown by `/search`.
+const SEARCH_LIMIT: usize = 20;
+
+fn search<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        let Some(dir) = session.state.config.transcript_dir.clone() else {
+            session.reply("* there is no transcript to search\n");
+            return Ok(());
+        };
+        if arg.is_empty() {
+            session.reply("* usage: /search <term>\n");
+            return Ok(());
+        }
+
+        let query = Query {
+            keyword: Some(arg.to_string()),
+            limit: Some(SEARCH_LIMIT),
+            ..Query::default()
+        };
+        // the archive can be large, keep it from blocking other connections
+        let entries =
+            tokio::task::spawn_blocking(move || transcript::search(&dir, &query)).await??;
+
+        if entries.is_empty() {
+            session.reply(format!("* nothing found for {arg}\n"));
+        }
+        for entry in entries {
+            session.reply(format!("* {entry}\n"));
+        }
+        Ok(())
+    })
+}
+
+fn oper<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        if session.state.moderation.check_password(arg) {
+            session.operator = true;
+            session.reply("* you are now an operator\n");
+        } else {
+            session.reply("* wrong password\n");
+        }
+        Ok(())
+    })
+}
+
+/// Only let operators go on, telling everyone else why not.
+fn require_operator(session: &mut Session) -> bool {
+    if !session.operator {
+        session.reply("* only operators can do that\n");
+    }
+    session.operator
+}
+
+/// Disconnect `name` if they are online. Returns `false` if they aren't.
+async fn disconnect(session: &Session, name: &str, reason: String) -> bool {
+    let Some(tx) = session
+        .state
+        .users
+        .read()
+        .await
+        .get(&name.to_string())
+        .map(|user| user.tx.clone())
+    else {
+        return false;
+    };
+
+    let msg = Msg {
+        from: session.name.clone(),
+        peer: None,
+        content: Arc::new(reason),
+        kind: MsgKind::Kick,
+    };
+    // a user too far behind to take the kick is disconnected once they catch up
+    tx.send(msg).await.is_ok()
+}
+
+/// Every user online who is banned by `target`.
+async fn banned_users(session: &Session, target: &Target) -> Vec<UserName> {
+    let policy = session.state.config.names;
+    let users = session.state.users.read().await;
+    users
+        .iter()
+        .filter(|(name, user)| match target {
+            Target::Name(banned) => policy.is_taken(name, [banned.as_str()]),
+            Target::Ip(ip) => user.peer.map(|peer| peer.ip()) == Some(*ip),
+        })
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+fn kick<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        if !require_operator(session) {
+            return Ok(());
+        }
+
+        let reason = format!("* you were kicked by {}\n", session.name);
+        if !disconnect(session, arg, reason).await {
+            session.reply(format!("* no user named {arg}\n"));
+            return Ok(());
+        }
+
+        let announcement = format!("* {arg} was kicked by {}\n", session.name);
+        session.state.rooms.announce(&announcement).await;
+        Ok(())
+    })
+}
+
+fn ban<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        if !require_operator(session) {
+            return Ok(());
+        }
+        if arg.is_empty() {
+            session.reply("* usage: /ban <user|ip>\n");
+            return Ok(());
+        }
+
+        let target = Target::parse(arg);
+        if !session.state.moderation.ban(&target).await? {
+            session.reply(format!("* {arg} is already banned\n"));
+            return Ok(());
+        }
+
+        let reason = format!("* you were banned by {}\n", session.name);
+        for name in banned_users(session, &target).await {
+            disconnect(session, &name, reason.clone()).await;
+        }
+
+        let announcement = format!("* {arg} was banned by {}\n", session.name);
+        session.state.rooms.announce(&announcement).await;
+        Ok(())
+    })
+}
+
+fn unban<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        if !require_operator(session) {
+            return Ok(());
+        }
+
+        if !session.state.moderation.unban(&Target::parse(arg)).await? {
+            session.reply(format!("* {arg} is not banned\n"));
+            return Ok(());
+        }
+
+        let announcement = format!("* {arg} was unbanned by {}\n", session.name);
+        session.state.rooms.announce(&announcement).await;
+        Ok(())
+    })
+}
+
+fn mute<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        if !require_operator(session) {
+            return Ok(());
+        }
+
+        let name = Arc::new(arg.to_string());
+        if !session.state.users.read().await.contains_key(&name) {
+            session.reply(format!("* no user named {arg}\n"));
+            return Ok(());
+        }
+        if !session.state.moderation.mute(name).await {
+            session.reply(format!("* {arg} is already muted\n"));
+            return Ok(());
+        }
+
+        let announcement = format!("* {arg} was muted by {}\n", session.name);
+        session.state.rooms.announce(&announcement).await;
+        Ok(())
+    })
+}
+
+fn unmute<'a>(session: &'a mut Session, arg: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        if !require_operator(session) {
+            return Ok(());
+        }
+
+        if !session
+            .state
+            .moderation
+            .unmute(&Arc::new(arg.to_string()))
+            .await
+        {
+            session.reply(format!("* {arg} is not muted\n"));
+            return Ok(());
+        }
+
+        let announcement = format!("* {arg} was unmuted by {}\n", session.name);
+        session.state.rooms.announce(&announcement).await;
+        Ok(())
+    })
+}
+
+fn help<'a>(session: &'a mut Session, _: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+        let commands = session.state.commands.clone();
+        for (name, command) in commands.0.iter() {
+            let usage = match command.usage {
+                "" => format!("/{name}"),
+                usage => format!("/{name} {usage}"),
+            };
+            session.reply(format!("* {usage}: {}\n", command.help));
+        }
+        Ok(())
+    })
+}
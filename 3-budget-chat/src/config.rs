@@ -0,0 +1,156 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::bail;
+
+use crate::{flood::FloodConfig, lang::Lang, text::NamePolicy, transcript};
+
+/// Server settings, configured with `budget-chat <addr> [--key=value]...`:
+///
+/// - `--max-lags=<n>`: disconnect users who fell behind the room more than `n` times, by default
+///   they are only told how many messages they missed
+/// - `--history-size=<n>`: number of messages kept per room for `/history`, defaults to 100
+/// - `--history-on-join=<n>`: number of messages sent to users entering a room, defaults to 0
+/// - `--history-file=<path>`: append every message to `path`, and read it back on startup
+/// - `--ws=<addr>`: also accept WebSocket clients on `addr`
+/// - `--irc=<addr>`: also accept IRC clients on `addr`
+/// - `--names=<policy>`: which names are accepted, `ascii` (the default) or `unicode`, see
+///   [`NamePolicy`]
+/// - `--name-retries=<n>`: let TCP and WebSocket users try another name `n` times after choosing
+///   one they can't have, instead of disconnecting them right away, defaults to 0
+/// - `--lang=<lang>`: language of the messages sent while choosing a name, `en` (the default),
+///   `de` or `fr`
+/// - `--operators=<name>,...`: users who are operators as soon as they join
+/// - `--oper-password=<password>`: lets anyone become an operator with `/oper <password>`
+/// - `--bans-file=<path>`: keep the list of bans in `path`
+/// - `--name-timeout-secs=<n>`: disconnect users who didn't choose a name within `n` seconds
+/// - `--idle-timeout-secs=<n>`: disconnect users who didn't send anything for `n` seconds
+/// - `--idle-warning-secs=<n>`: warn idle users this long before disconnecting them, shorter than
+///   the idle timeout, defaults to 30 or half the idle timeout if that is shorter
+/// - `--server-name=<name>`: name of this server for linked servers, defaults to `budgetchat`
+/// - `--federation=<addr>`: accept links from the servers of `--peers` on `addr`
+/// - `--peers=<addr>,...`: link to other servers
+/// - `--transcript-dir=<dir>`: keep a transcript of all rooms in `dir`
+/// - `--transcript-max-bytes=<n>`: size at which transcript files are rotated, defaults to 1 MiB
+/// - `--events=<addr>`: send the event stream to anyone connecting to `addr`, see
+///   [`crate::events`]
+/// - `--events-file=<path>`: append the event stream to `path`
+/// - `--bots=<name>,...`: bots to start, see [`crate::bot::from_config`]
+/// - `--ticket-feed=<path>`: tickets exported by speed-daemon, for the `tickets` bot
+///
+/// and the flood protection flags of [`FloodConfig`].
+#[derive(Debug)]
+pub struct Config {
+    pub max_lags: Option<u32>,
+    pub history_size: usize,
+    pub history_on_join: usize,
+    pub history_file: Option<PathBuf>,
+    pub ws: Option<SocketAddr>,
+    pub irc: Option<SocketAddr>,
+    pub flood: FloodConfig,
+    pub names: NamePolicy,
+    pub name_retries: u32,
+    pub lang: Lang,
+    pub operators: Vec<String>,
+    pub oper_password: Option<String>,
+    pub bans_file: Option<PathBuf>,
+    pub name_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub idle_warning: Duration,
+    pub server_name: String,
+    pub federation: Option<SocketAddr>,
+    pub peers: Vec<SocketAddr>,
+    pub transcript_dir: Option<PathBuf>,
+    pub transcript_max_bytes: u64,
+    pub events: Option<SocketAddr>,
+    pub events_file: Option<PathBuf>,
+    pub bots: Vec<String>,
+    pub ticket_feed: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_lags: None,
+            history_size: 100,
+            history_on_join: 0,
+            history_file: None,
+            ws: None,
+            irc: None,
+            flood: FloodConfig::default(),
+            names: NamePolicy::default(),
+            name_retries: 0,
+            lang: Lang::default(),
+            operators: Vec::new(),
+            oper_password: None,
+            bans_file: None,
+            name_timeout: None,
+            idle_timeout: None,
+            idle_warning: Duration::from_secs(30),
+            server_name: "budgetchat".to_string(),
+            federation: None,
+            peers: Vec::new(),
+            transcript_dir: None,
+            transcript_max_bytes: transcript::DEFAULT_MAX_BYTES,
+            events: None,
+            events_file: None,
+            bots: Vec::new(),
+            ticket_feed: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(args: &util::Args) -> anyhow::Result<Self> {
+        let default = Self::default();
+
+        let idle_timeout = args.value("idle-timeout-secs")?.map(Duration::from_secs);
+        let idle_warning = match (args.value("idle-warning-secs")?, idle_timeout) {
+            (Some(warning), Some(timeout)) if Duration::from_secs(warning) >= timeout => {
+                bail!("--idle-warning-secs has to be shorter than --idle-timeout-secs")
+            }
+            (Some(warning), _) => Duration::from_secs(warning),
+            // the default would warn as soon as users join with a short timeout
+            (None, Some(timeout)) => default.idle_warning.min(timeout / 2),
+            (None, None) => default.idle_warning,
+        };
+
+        Ok(Self {
+            max_lags: args.value("max-lags")?,
+            history_size: args.value_or("history-size", default.history_size)?,
+            history_on_join: args.value_or("history-on-join", default.history_on_join)?,
+            history_file: args.value("history-file")?,
+            ws: args.value("ws")?,
+            irc: args.value("irc")?,
+            flood: FloodConfig::from_args(args)?,
+            names: args.value_or("names", default.names)?,
+            name_retries: args.value_or("name-retries", default.name_retries)?,
+            lang: args.value_or("lang", default.lang)?,
+            operators: args
+                .value::<String>("operators")?
+                .map(|names| names.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+            oper_password: args.value("oper-password")?,
+            bans_file: args.value("bans-file")?,
+            name_timeout: args.value("name-timeout-secs")?.map(Duration::from_secs),
+            idle_timeout,
+            idle_warning,
+            server_name: args.value_or("server-name", default.server_name)?,
+            federation: args.value("federation")?,
+            peers: args
+                .value::<String>("peers")?
+                .map(|peers| peers.split(',').map(str::parse).collect())
+                .transpose()?
+                .unwrap_or_default(),
+            transcript_dir: args.value("transcript-dir")?,
+            transcript_max_bytes: args
+                .value_or("transcript-max-bytes", default.transcript_max_bytes)?,
+            events: args.value("events")?,
+            events_file: args.value("events-file")?,
+            bots: args
+                .value::<String>("bots")?
+                .map(|bots| bots.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+            ticket_feed: args.value("ticket-feed")?,
+        })
+    }
+}
@@ -0,0 +1,201 @@
+//! Stream of what happens in the chat, one JSON object per line, for other tools to consume.
+//!
+//! Enabled with `--events=<addr>`, to send it to anyone connecting to `addr`, and/or
+//! `--events-file=<path>`, to append it to `path`. Every object has `at`, the time in RFC 3339 and
+//! UTC, and `event`, one of:
+//!
+//! - `user_joined` and `user_left`, with `room`, `user` and `peer`
+//! - `message`, with `room`, `user`, `peer` and `text`
+//! - `rejected_join`, with `name`, `peer` and `reason`, one of `empty`, `invalid_chars`,
+//!   `too_long`, `duplicate` or `banned`
+//!
+//! `peer` is the address the user is connected from, `null` for bots and users of linked
+//! servers. Everything but rejected joins is read from the broadcast channels of the rooms, so
+//! it is exactly what the users in the room are sent.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{error, info, warn};
+
+use crate::{config::Config, room::RoomName, text::Rejection, Msg, MsgKind};
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    UserJoined {
+        room: &'a str,
+        user: &'a str,
+        peer: Option<SocketAddr>,
+    },
+    UserLeft {
+        room: &'a str,
+        user: &'a str,
+        peer: Option<SocketAddr>,
+    },
+    Message {
+        room: &'a str,
+        user: &'a str,
+        peer: Option<SocketAddr>,
+        text: &'a str,
+    },
+    RejectedJoin {
+        name: &'a str,
+        peer: Option<SocketAddr>,
+        reason: &'a str,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    at: String,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+/// Publishes the event stream, or does nothing if it is disabled.
+pub struct Events(Option<broadcast::Sender<Arc<String>>>);
+
+impl Events {
+    pub fn disabled() -> Self {
+        Self(None)
+    }
+
+    /// Start sending the stream where `config` asks for, if anywhere.
+    pub async fn open(config: &Config) -> anyhow::Result<Self> {
+        if config.events.is_none() && config.events_file.is_none() {
+            return Ok(Self::disabled());
+        }
+
+        let (tx, _rx) = broadcast::channel(1024);
+
+        if let Some(path) = &config.events_file {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            tokio::spawn(write_file(file, tx.subscribe()));
+        }
+        if let Some(addr) = config.events {
+            let listener = TcpListener::bind(addr).await?;
+            tokio::spawn(serve(listener, tx.clone()));
+        }
+
+        Ok(Self(Some(tx)))
+    }
+
+    /// Follow everything sent to a room, from when it is created until it is removed.
+    pub fn watch(&self, room: RoomName, mut rx: broadcast::Receiver<Msg>) {
+        let Some(tx) = self.0.clone() else {
+            return;
+        };
+
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(msg) => publish_msg(&tx, &room, &msg),
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("event stream missed {n} messages of room {room}")
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+
+    /// Someone connected from `peer` couldn't join as `name`.
+    pub fn rejected_join(&self, name: &str, peer: Option<SocketAddr>, rejection: Rejection) {
+        let Some(tx) = &self.0 else {
+            return;
+        };
+
+        let reason = rejection.code();
+        publish(tx, Event::RejectedJoin { name, peer, reason });
+    }
+}
+
+fn publish_msg(tx: &broadcast::Sender<Arc<String>>, room: &str, msg: &Msg) {
+    let (user, peer) = (msg.from.as_str(), msg.peer);
+    let event = match &msg.kind {
+        MsgKind::Arrival => Event::UserJoined { room, user, peer },
+        MsgKind::Departure => Event::UserLeft { room, user, peer },
+        MsgKind::Chat(text) => Event::Message {
+            room,
+            user,
+            peer,
+            text,
+        },
+        _ => return,
+    };
+    publish(tx, event);
+}
+
+fn publish(tx: &broadcast::Sender<Arc<String>>, event: Event) {
+    let line = Line {
+        at: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+        event,
+    };
+
+    match serde_json::to_string(&line) {
+        // sending only fails if nobody is listening
+        Ok(json) => {
+            let _ = tx.send(Arc::new(json + "\n"));
+        }
+        Err(e) => error!("failed to serialize event: {e}"),
+    }
+}
+
+async fn write_file(mut file: File, mut rx: broadcast::Receiver<Arc<String>>) {
+    loop {
+        match rx.recv().await {
+            Ok(line) => {
+                if let Err(e) = file.write_all(line.as_bytes()) {
+                    error!("failed to write event stream: {e}");
+                }
+            }
+            Err(RecvError::Lagged(n)) => warn!("event stream file missed {n} events"),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn serve(listener: TcpListener, tx: broadcast::Sender<Arc<String>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                info!("accepted event stream connection from {addr}");
+                tokio::spawn(stream_to(stream, addr, tx.subscribe()));
+            }
+            Err(e) => error!("failed to accept event stream connection: {e}"),
+        }
+    }
+}
+
+async fn stream_to(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    mut rx: broadcast::Receiver<Arc<String>>,
+) {
+    loop {
+        match rx.recv().await {
+            Ok(line) => {
+                if stream.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            Err(RecvError::Lagged(n)) => warn!("event stream to {addr} missed {n} events"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+    info!("closed event stream connection from {addr}");
+}
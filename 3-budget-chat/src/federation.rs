@@ -0,0 +1,552 @@
+//! Links between budget-chat servers, so their users can talk to each other.
+//!
+//! Linked servers tell each other what their own users do, as tab separated lines:
+//!
+//! - `HELLO <server>`, sent first
+//! - `JOIN <room> <user>`
+//! - `PART <room> <user>`
+//! - `MSG <room> <user> <text>`
+//! - `NICK <room> <old> <new>`
+//!
+//! Events received over a link are never passed on, so every server has to be linked with every
+//! other one, and links are only accepted from the addresses of `--peers`. There is at most one
+//! link with each server: if two servers connect to each other, the link dialed by the one with
+//! the lexically smaller name is kept. Remote users show up in rooms like local ones, as
+//! `<user>@<server>` if their name is already taken here, and leave all at once when their link is
+//! lost. Names banned here are not shown.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::tcp::OwnedWriteHalf,
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, oneshot, Mutex, OwnedMutexGuard, RwLock},
+};
+use tracing::{info, warn};
+
+use crate::{
+    room::{is_valid_room_name, RoomName},
+    text::{self, NamePolicy},
+    Msg, MsgConent, MsgKind, State, UserName,
+};
+
+/// How long to wait before connecting to a peer again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Something a local user did.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Join {
+        room: RoomName,
+        user: UserName,
+    },
+    Leave {
+        room: RoomName,
+        user: UserName,
+    },
+    Say {
+        room: RoomName,
+        user: UserName,
+        text: MsgConent,
+    },
+    Rename {
+        room: RoomName,
+        old: UserName,
+        new: UserName,
+    },
+}
+
+pub struct Federation {
+    name: String,
+    tx: broadcast::Sender<Event>,
+    /// Names of all users from other servers, as shown here.
+    remote: RwLock<HashSet<UserName>>,
+    /// The link with each server, by its name.
+    links: Mutex<HashMap<String, Slot>>,
+}
+
+/// The link kept with a server.
+struct Slot {
+    /// Whether the link was dialed by the server with the smaller name.
+    preferred: bool,
+    /// Closes the link, when it is replaced by the preferred one.
+    close: oneshot::Sender<()>,
+    /// Held by the link until its users left, so a link replacing it waits for that.
+    up: Arc<Mutex<()>>,
+}
+
+impl Federation {
+    pub fn new(name: String) -> Self {
+        let (tx, _rx) = broadcast::channel(1024);
+        Self {
+            name,
+            tx,
+            remote: RwLock::default(),
+            links: Mutex::default(),
+        }
+    }
+
+    /// Tell every linked server.
+    pub fn publish(&self, event: Event) {
+        // sending only fails if there are no links
+        let _ = self.tx.send(event);
+    }
+
+    /// Whether `name` can't be told apart from the name of a user of a linked server.
+    pub async fn is_remote(&self, policy: NamePolicy, name: &str) -> bool {
+        let remote = self.remote.read().await;
+        policy.is_taken(name, remote.iter().map(|user| user.as_str()))
+    }
+
+    /// Keep a new link with `server`, closing the current one if the new one is preferred.
+    ///
+    /// Returns what the link has to hold while it is up, and what closes it. If the current link is
+    /// kept instead, returns the mutex it holds, so the new one can wait for it to go down.
+    async fn register(
+        &self,
+        server: &str,
+        preferred: bool,
+    ) -> Result<(OwnedMutexGuard<()>, oneshot::Receiver<()>), Arc<Mutex<()>>> {
+        let (close_tx, close_rx) = oneshot::channel();
+
+        let up = {
+            let mut links = self.links.lock().await;
+            match links.get_mut(server) {
+                Some(slot) if slot.preferred || !preferred => return Err(slot.up.clone()),
+                Some(slot) => {
+                    slot.preferred = true;
+                    let close = std::mem::replace(&mut slot.close, close_tx);
+                    // the current link may be gone already
+                    let _ = close.send(());
+                    slot.up.clone()
+                }
+                None => {
+                    let up = Arc::new(Mutex::new(()));
+                    let guard = up.clone().try_lock_owned().expect("new mutex");
+                    let slot = Slot {
+                        preferred,
+                        close: close_tx,
+                        up,
+                    };
+                    links.insert(server.to_string(), slot);
+                    return Ok((guard, close_rx));
+                }
+            }
+        };
+
+        Ok((up.lock_owned().await, close_rx))
+    }
+
+    /// Forget the link with `server` once it is down, unless it was replaced already.
+    async fn unregister(&self, server: &str, preferred: bool) {
+        let mut links = self.links.lock().await;
+        if links
+            .get(server)
+            .is_some_and(|slot| slot.preferred == preferred)
+        {
+            links.remove(server);
+        }
+    }
+}
+
+/// Accept links from other servers, only from the addresses of `--peers`.
+pub async fn listen(listener: TcpListener, state: State) -> anyhow::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        // peers connect from any port, only their address is known
+        if !state.config.peers.iter().any(|peer| peer.ip() == addr.ip()) {
+            warn!("refused federation link from {addr}, not a peer");
+            continue;
+        }
+        info!("accepted federation link from {addr}");
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = link(stream, false, state).await {
+                warn!("federation link with {addr} failed: {e}");
+            }
+            info!("closing federation link with {addr}");
+        });
+    }
+}
+
+/// Keep a link to `addr` up, reconnecting whenever it is lost.
+pub async fn connect(addr: SocketAddr, state: State) {
+    loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => {
+                info!("connected federation link to {addr}");
+                if let Err(e) = link(stream, true, state.clone()).await {
+                    warn!("federation link with {addr} failed: {e}");
+                }
+                info!("federation link with {addr} lost");
+            }
+            Err(e) => warn!("failed to connect to {addr}: {e}"),
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Run a link until it is lost. `dialed` is whether this server connected to the other one.
+async fn link(stream: TcpStream, dialed: bool, state: State) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let name = &state.federation.name;
+    writer
+        .write_all(format!("HELLO\t{name}\n").as_bytes())
+        .await?;
+    let server = match lines.next_line().await? {
+        Some(line) => match line.split_once('\t') {
+            Some(("HELLO", server)) => server.to_string(),
+            _ => bail!("expected HELLO, got {line:?}"),
+        },
+        None => return Ok(()),
+    };
+    if server == *name {
+        bail!("linked to a server named {server:?} too");
+    }
+
+    // both ends agree on it, so when both servers connected they keep the same link
+    let preferred = dialed == (name.as_str() < server.as_str());
+    let (_up, mut close) = match state.federation.register(&server, preferred).await {
+        Ok(link) => link,
+        Err(up) => {
+            info!("already linked with {server}");
+            drop((lines, writer));
+            if dialed {
+                // connecting again is pointless until the other link is lost
+                let _ = up.lock().await;
+            }
+            return Ok(());
+        }
+    };
+
+    // subscribe before taking the snapshot, so no event falls in between
+    let mut events = state.federation.tx.subscribe();
+
+    let mut remote = Remote {
+        server,
+        users: HashMap::new(),
+    };
+
+    let res = async {
+        send_local_users(&mut writer, &state).await?;
+
+        loop {
+            tokio::select! {
+                res_line_opt = lines.next_line() => {
+                    let Some(line) = res_line_opt? else {
+                        return Ok(());
+                    };
+                    remote.apply(&state, &line).await?;
+                }
+                res_event = events.recv() => match res_event {
+                    Ok(event) => writer.write_all(encode(&event).as_bytes()).await?,
+                    // the other side would be out of sync, start over with a new link
+                    Err(broadcast::error::RecvError::Lagged(_)) => bail!("link fell behind"),
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = &mut close => {
+                    info!("replacing link with {}", remote.server);
+                    return Ok(());
+                }
+            }
+        }
+    }
+    .await;
+
+    // make absence of everyone from the other side noticed, however the link ended
+
+    remote.leave_all(&state).await;
+    state.federation.unregister(&remote.server, preferred).await;
+
+    res
+}
+
+/// Tell the other side about everyone here.
+async fn send_local_users(writer: &mut OwnedWriteHalf, state: &State) -> anyhow::Result<()> {
+    let mut out = String::new();
+    for (room, users) in local_users(state).await {
+        for user in users {
+            out.push_str(&encode(&Event::Join {
+                room: room.clone(),
+                user,
+            }));
+        }
+    }
+    writer.write_all(out.as_bytes()).await?;
+    Ok(())
+}
+
+/// Every room with the local users in it.
+async fn local_users(state: &State) -> Vec<(RoomName, Vec<UserName>)> {
+    let mut res = Vec::new();
+    for (room, _) in state.rooms.list().await {
+        let mut users = state.rooms.users(&room).await;
+        {
+            let lock = state.users.read().await;
+            users.retain(|user| lock.contains_key(user));
+        }
+        res.push((room, users));
+    }
+    res
+}
+
+fn encode(event: &Event) -> String {
+    match event {
+        Event::Join { room, user } => format!("JOIN\t{room}\t{user}\n"),
+        Event::Leave { room, user } => format!("PART\t{room}\t{user}\n"),
+        Event::Say { room, user, text } => format!("MSG\t{room}\t{user}\t{text}\n"),
+        Event::Rename { room, old, new } => format!("NICK\t{room}\t{old}\t{new}\n"),
+    }
+}
+
+struct RemoteUser {
+    /// Name as shown here.
+    display: UserName,
+    room: RoomName,
+}
+
+/// Users from the other side of a link, by their name over there.
+struct Remote {
+    server: String,
+    users: HashMap<String, RemoteUser>,
+}
+
+impl Remote {
+    async fn apply(&mut self, state: &State, line: &str) -> anyhow::Result<()> {
+        let mut parts = line.splitn(4, '\t');
+        let command = parts.next().unwrap_or_default();
+
+        let room = parts
+            .next()
+            .ok_or_else(|| anyhow!("{command} without room"))?;
+        if !is_valid_room_name(room) {
+            bail!("invalid room {room}");
+        }
+        let room = RoomName::new(room.to_string());
+        let user = parts
+            .next()
+            .ok_or_else(|| anyhow!("{command} without user"))?;
+
+        match (command, parts.next()) {
+            ("JOIN", None) => self.join(state, room, user).await,
+            ("PART", None) => {
+                if let Some(remote) = self.users.remove(user) {
+                    state.rooms.leave(&remote.room, &remote.display, None).await;
+                    state
+                        .federation
+                        .remote
+                        .write()
+                        .await
+                        .remove(&remote.display);
+                }
+            }
+            ("MSG", Some(text)) => {
+                let Some(remote) = self.users.get(user).filter(|remote| remote.room == room) else {
+                    return Ok(());
+                };
+                let text = text::sanitize(text);
+                let msg = Msg {
+                    from: remote.display.clone(),
+                    peer: None,
+                    content: MsgConent::new(format!("[{}] {text}\n", remote.display)),
+                    kind: MsgKind::Chat(MsgConent::new(text.to_string())),
+                };
+                // only fails if nobody here is in the room
+                let _ = state.rooms.say(&room, msg).await;
+            }
+            ("NICK", Some(new)) => self.rename(state, user, new).await?,
+            _ => bail!("invalid federation line {line:?}"),
+        }
+
+        Ok(())
+    }
+
+    async fn join(&mut self, state: &State, room: RoomName, user: &str) {
+        if let Some(remote) = self.users.get_mut(user) {
+            if remote.room != room {
+                state.rooms.leave(&remote.room, &remote.display, None).await;
+                state.rooms.join(&room, &remote.display, None, 0).await;
+                remote.room = room;
+            }
+            return;
+        }
+
+        let Some(display) = self.reserve(state, user).await else {
+            return;
+        };
+        state.rooms.join(&room, &display, None, 0).await;
+        self.users
+            .insert(user.to_string(), RemoteUser { display, room });
+    }
+
+    async fn rename(&mut self, state: &State, old: &str, new: &str) -> anyhow::Result<()> {
+        let Some(mut remote) = self.users.remove(old) else {
+            return Ok(());
+        };
+        let Some(display) = self.reserve(state, new).await else {
+            // nobody can see the user under their new name, so they are gone
+            state.rooms.leave(&remote.room, &remote.display, None).await;
+            state
+                .federation
+                .remote
+                .write()
+                .await
+                .remove(&remote.display);
+            return Ok(());
+        };
+
+        state
+            .federation
+            .remote
+            .write()
+            .await
+            .remove(&remote.display);
+        state
+            .rooms
+            .rename(&remote.room, &remote.display, &display)
+            .await;
+        remote.display = display;
+        self.users.insert(new.to_string(), remote);
+        Ok(())
+    }
+
+    /// Pick the name `user` is shown as here, and keep it from being used by anyone else.
+    async fn reserve(&self, state: &State, user: &str) -> Option<UserName> {
+        let policy = state.config.names;
+        let Ok(user) = policy.validate(user) else {
+            warn!("ignoring remote user with invalid name {user:?}");
+            return None;
+        };
+        if state.moderation.is_banned_name(policy, &user).await {
+            warn!("ignoring remote user with banned name {user:?}");
+            return None;
+        }
+
+        let server = &self.server;
+        let candidates = [user.to_string(), format!("{user}@{server}")];
+
+        // hold the local users lock, so no local user can take the name in the meantime
+        let users = state.users.read().await;
+        let mut remote = state.federation.remote.write().await;
+
+        let display = candidates.into_iter().map(Arc::new).find(|name| {
+            let taken = users.keys().chain(remote.iter()).map(|user| user.as_str());
+            !policy.is_taken(name, taken)
+        });
+        match &display {
+            Some(display) => {
+                remote.insert(display.clone());
+            }
+            None => warn!("ignoring remote user {user}@{server}, the name is taken"),
+        }
+        display
+    }
+
+    async fn leave_all(&mut self, state: &State) {
+        for (_, remote) in self.users.drain() {
+            state.rooms.leave(&remote.room, &remote.display, None).await;
+            state
+                .federation
+                .remote
+                .write()
+                .await
+                .remove(&remote.display);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::Lines,
+        net::tcp::OwnedReadHalf,
+        time::{sleep, timeout},
+    };
+
+    use super::*;
+    use crate::tests::{serve, state};
+
+    /// A chat user, joined as soon as it is connected.
+    struct User {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl User {
+        async fn join(addr: SocketAddr, name: &str) -> Self {
+            let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+            let mut user = Self {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            };
+            user.lines.next_line().await.unwrap();
+            user.send(name).await;
+            user
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.writer
+                .write_all(format!("{line}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        /// Everything received until nothing came for a while.
+        async fn received(&mut self) -> Vec<String> {
+            let mut res = Vec::new();
+            while let Ok(line) = timeout(Duration::from_millis(300), self.lines.next_line()).await {
+                res.push(line.unwrap().unwrap());
+            }
+            res
+        }
+    }
+
+    /// Two servers named `a` and `b`, connecting to each other, with their chat addresses.
+    async fn linked_pair() -> (SocketAddr, SocketAddr) {
+        let listener_a = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener_b = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr_a = listener_a.local_addr().unwrap();
+        let addr_b = listener_b.local_addr().unwrap();
+
+        let a = state(&["--server-name=a", &format!("--peers={addr_b}")]);
+        let b = state(&["--server-name=b", &format!("--peers={addr_a}")]);
+        tokio::spawn(listen(listener_a, a.clone()));
+        tokio::spawn(listen(listener_b, b.clone()));
+        tokio::spawn(connect(addr_b, a.clone()));
+        tokio::spawn(connect(addr_a, b.clone()));
+
+        // let the link dialed by b give way to the one dialed by a
+        sleep(Duration::from_millis(500)).await;
+        assert_eq!(a.federation.links.lock().await.len(), 1);
+        assert_eq!(b.federation.links.lock().await.len(), 1);
+
+        (serve(a).await, serve(b).await)
+    }
+
+    #[tokio::test]
+    async fn one_link_between_servers_dialing_each_other() {
+        let (addr_a, addr_b) = linked_pair().await;
+
+        let mut bob = User::join(addr_b, "bob").await;
+        bob.received().await;
+        let mut alice = User::join(addr_a, "alice").await;
+        assert_eq!(alice.received().await, ["* the room contains: bob"]);
+        assert_eq!(bob.received().await, ["* alice has entered the room"]);
+
+        bob.send("hi").await;
+        assert_eq!(alice.received().await, ["[bob] hi"]);
+        alice.send("hello").await;
+        assert_eq!(bob.received().await, ["[alice] hello"]);
+    }
+}
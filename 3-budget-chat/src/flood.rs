@@ -0,0 +1,170 @@
+//! Per-user flood protection, applied to every line before it is handled.
+//!
+//! Lines are paid for from a token bucket. Lines sent without a token, or longer than allowed,
+//! are dropped with a warning and count as a strike. Too many strikes mute the user for a while,
+//! and users who keep getting muted are disconnected.
+
+use std::time::Duration;
+
+use anyhow::bail;
+use tokio::time::Instant;
+
+/// The spec requires names and messages of at least 1000 characters to be accepted.
+pub const MIN_MAX_LEN: usize = 1000;
+
+/// Configured with:
+///
+/// - `--rate=<n>`: lines per second every user can send, unlimited by default
+/// - `--burst=<n>`: lines which can be sent at once before `--rate` applies, defaults to 10
+/// - `--max-len=<n>`: longest accepted line in characters, at least 1000, unlimited by default
+///   (users sending a line which can't be this short anymore are disconnected while it is read, see
+///   [`crate::lines`])
+/// - `--mute-after=<n>`: strikes before a user is muted, defaults to 3
+/// - `--mute-secs=<n>`: how long users are muted, defaults to 30
+/// - `--max-mutes=<n>`: mutes before a user is disconnected, defaults to 3
+#[derive(Debug, Clone)]
+pub struct FloodConfig {
+    pub rate: Option<f64>,
+    pub burst: u32,
+    pub max_len: Option<usize>,
+    pub mute_after: u32,
+    pub mute: Duration,
+    pub max_mutes: u32,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            rate: None,
+            burst: 10,
+            max_len: None,
+            mute_after: 3,
+            mute: Duration::from_secs(30),
+            max_mutes: 3,
+        }
+    }
+}
+
+impl FloodConfig {
+    pub fn from_args(args: &util::Args) -> anyhow::Result<Self> {
+        let default = Self::default();
+
+        let config = Self {
+            rate: args.value("rate")?,
+            burst: args.value_or("burst", default.burst)?.max(1),
+            max_len: args.value("max-len")?,
+            mute_after: args.value_or("mute-after", default.mute_after)?.max(1),
+            mute: Duration::from_secs(args.value_or("mute-secs", default.mute.as_secs())?),
+            max_mutes: args.value_or("max-mutes", default.max_mutes)?,
+        };
+
+        if let Some(rate) = config.rate {
+            if rate.is_nan() || rate <= 0.0 {
+                bail!("--rate has to be positive");
+            }
+        }
+        if let Some(max_len) = config.max_len {
+            if max_len < MIN_MAX_LEN {
+                bail!("--max-len has to be at least {MIN_MAX_LEN}");
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+pub enum Verdict {
+    Accept,
+    /// Drop the line, telling the user why.
+    Reject(String),
+    /// Drop the line and disconnect the user, telling them why.
+    Disconnect(String),
+}
+
+pub struct Flood {
+    tokens: f64,
+    refilled_at: Instant,
+    strikes: u32,
+    mutes: u32,
+    muted_until: Option<Instant>,
+}
+
+impl Flood {
+    pub fn new(config: &FloodConfig) -> Self {
+        Self {
+            tokens: config.burst as f64,
+            refilled_at: Instant::now(),
+            strikes: 0,
+            mutes: 0,
+            muted_until: None,
+        }
+    }
+
+    pub fn check(&mut self, config: &FloodConfig, line: &str) -> Verdict {
+        let now = Instant::now();
+
+        if let Some(until) = self.muted_until {
+            if now < until {
+                let secs = (until - now).as_secs() + 1;
+                return Verdict::Reject(format!("* you are muted for {secs} more seconds\n"));
+            }
+            self.muted_until = None;
+        }
+
+        if let Some(max_len) = config.max_len {
+            if line.chars().count() > max_len {
+                return self.strike(
+                    config,
+                    now,
+                    format!("* message is too long, at most {max_len} characters allowed\n"),
+                );
+            }
+        }
+
+        let Some(rate) = config.rate else {
+            return Verdict::Accept;
+        };
+
+        let burst = config.burst as f64;
+        let elapsed = (now - self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return self.strike(
+                config,
+                now,
+                "* slow down, you are sending too fast\n".to_string(),
+            );
+        }
+
+        // a user who let the bucket fill up again is not flooding anymore
+        if self.tokens == burst {
+            self.strikes = 0;
+        }
+        self.tokens -= 1.0;
+
+        Verdict::Accept
+    }
+
+    fn strike(&mut self, config: &FloodConfig, now: Instant, warning: String) -> Verdict {
+        self.strikes += 1;
+        if self.strikes < config.mute_after {
+            return Verdict::Reject(warning);
+        }
+
+        self.strikes = 0;
+        self.mutes += 1;
+        if self.mutes > config.max_mutes {
+            return Verdict::Disconnect(
+                "* disconnecting, you kept flooding the room\n".to_string(),
+            );
+        }
+
+        self.muted_until = Some(now + config.mute);
+        Verdict::Reject(format!(
+            "{warning}* you are muted for {} seconds\n",
+            config.mute.as_secs()
+        ))
+    }
+}
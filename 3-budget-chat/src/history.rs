@@ -0,0 +1,87 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use tracing::error;
+
+use crate::{room::RoomName, MsgConent};
+
+/// The last chat messages of every room, kept even when a room is removed for being empty.
+///
+/// If a file is given, every message is also appended to it as `<room>\t<message>`, and the file
+/// is read back on startup.
+pub struct History {
+    size: usize,
+    rooms: HashMap<RoomName, VecDeque<MsgConent>>,
+    file: Option<File>,
+}
+
+impl History {
+    pub fn open(size: usize, path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut history = Self {
+            size,
+            rooms: HashMap::new(),
+            file: None,
+        };
+
+        let Some(path) = path else {
+            return Ok(history);
+        };
+
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                let Some((room, content)) = line.split_once('\t') else {
+                    continue;
+                };
+                history.remember(
+                    &RoomName::new(room.to_string()),
+                    MsgConent::new(format!("{content}\n")),
+                );
+            }
+        }
+
+        history.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+
+        Ok(history)
+    }
+
+    pub fn push(&mut self, room: &RoomName, content: MsgConent) {
+        if let Some(file) = &mut self.file {
+            if let Err(e) = write!(file, "{room}\t{content}") {
+                error!("failed to persist history: {e}");
+            }
+        }
+
+        self.remember(room, content);
+    }
+
+    fn remember(&mut self, room: &RoomName, content: MsgConent) {
+        if self.size == 0 {
+            return;
+        }
+
+        let messages = self.rooms.entry(room.clone()).or_default();
+        if messages.len() == self.size {
+            messages.pop_front();
+        }
+        messages.push_back(content);
+    }
+
+    /// Up to `n` of the most recent messages of `room`, oldest first.
+    pub fn last(&self, room: &RoomName, n: usize) -> Vec<MsgConent> {
+        let Some(messages) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+
+        messages
+            .iter()
+            .skip(messages.len().saturating_sub(n))
+            .map(Arc::clone)
+            .collect()
+    }
+}
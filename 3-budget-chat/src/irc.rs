@@ -0,0 +1,606 @@
+//! IRC frontend, so the chat can be used from regular IRC clients.
+//!
+//! Every room is a channel named `#<room>`, and like TCP users, IRC users are in exactly one room
+//! at a time: joining a channel leaves the previous one, and parting a channel goes back to the
+//! lobby. Replies meant for TCP users, like the ones of `/` commands, are sent as notices.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc::error::TrySendError,
+    time::Instant,
+};
+use util::log_and_exit;
+
+use crate::{
+    admit,
+    flood::Verdict,
+    lines::BoundedLines,
+    room::{is_valid_room_name, RoomName},
+    session::{self, Session},
+    text::{self, Rejection},
+    with_timeout, Msg, MsgKind, State,
+};
+
+/// Name of the server, used as prefix of everything not sent by a user.
+const SERVER: &str = "budgetchat";
+
+pub async fn handle_stream(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    state: State,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut lines = BoundedLines::new(BufReader::new(reader), state.config.flood.max_len);
+
+    // wait for NICK and USER, and let the user in
+
+    let deadline = state
+        .config
+        .name_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let Some(mut session) = register(&mut lines, &mut writer, addr, state, deadline).await? else {
+        log_and_exit!(addr);
+    };
+
+    // welcome the user, then chat messages
+
+    let res = chat(&mut lines, &mut writer, &mut session).await;
+
+    // make absence noticed, remove from UsersList, however the chat ended
+
+    session.finish().await;
+
+    res?;
+    log_and_exit!(addr);
+}
+
+/// Wait for NICK and USER and admit the user, or return `None` if they quit or didn't register
+/// before `deadline`. Only reading the lines is timed out, so an admitted name is never left behind.
+async fn register<R, W>(
+    lines: &mut BoundedLines<R>,
+    writer: &mut W,
+    addr: SocketAddr,
+    state: State,
+    deadline: Option<Instant>,
+) -> anyhow::Result<Option<Session>>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut nick = None;
+    let mut user = false;
+
+    loop {
+        let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let Some(res_line_opt) = with_timeout(left, lines.next_line()).await else {
+            writer.write_all(b"ERROR :Registration timeout\r\n").await?;
+            return Ok(None);
+        };
+        let Some(line) = res_line_opt? else {
+            return Ok(None);
+        };
+        let (command, params) = parse(&line);
+
+        let mut out = String::new();
+        match (command.as_str(), params.as_slice()) {
+            ("CAP", [sub, ..]) if sub.eq_ignore_ascii_case("LS") => {
+                out.push_str(&format!(":{SERVER} CAP * LS :\r\n"));
+            }
+            ("CAP", _) | ("PONG", _) => {}
+            ("PING", params) => pong(&mut out, params),
+            ("NICK", [name, ..]) => nick = Some(name.to_string()),
+            ("USER", [_, ..]) => user = true,
+            ("QUIT", _) => return Ok(None),
+            (_, _) => numeric(&mut out, "451", "*", ":You have not registered"),
+        }
+
+        if let (Some(name), true) = (&nick, user) {
+            match admit(state.clone(), name.clone(), Some(addr)).await {
+                Ok(session) => return Ok(Some(session)),
+                Err(Rejection::Banned) => {
+                    numeric(&mut out, "465", "*", ":You are banned from this server");
+                }
+                Err(Rejection::Duplicate) => {
+                    numeric(
+                        &mut out,
+                        "433",
+                        "*",
+                        &format!("{name} :Nickname is already in use"),
+                    );
+                }
+                Err(_) => {
+                    numeric(&mut out, "432", "*", &format!("{name} :Erroneous nickname"));
+                }
+            }
+            nick = None;
+        }
+
+        writer.write_all(out.as_bytes()).await?;
+    }
+}
+
+async fn chat<R, W>(
+    lines: &mut BoundedLines<R>,
+    writer: &mut W,
+    session: &mut Session,
+) -> anyhow::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // written here so a failed write still ends with session.finish()
+    let mut out = String::new();
+    numeric(
+        &mut out,
+        "001",
+        &session.name,
+        &format!(":Welcome to budgetchat, {}", session.name),
+    );
+    joined(&mut out, session).await;
+    notices(&mut out, session);
+    writer.write_all(out.as_bytes()).await?;
+    out.clear();
+
+    loop {
+        let idle = session.idle_deadline();
+
+        let connected = tokio::select! {
+            res_msg_opt = lines.next_line() => {
+                let Some(line) = res_msg_opt? else {
+                    return Ok(());
+                };
+                handle_line(&mut out, session, &line).await?
+            }
+            res_msg = session.joined.rx.recv() => match res_msg {
+                Ok(msg) => {
+                    relay(&mut out, session, &msg);
+                    true
+                }
+                Err(e) => session.receive(Err(e)),
+            },
+            Some(msg) = session.direct_rx.recv() => match msg.kind {
+                MsgKind::Kick => session.receive_direct(msg),
+                _ => {
+                    relay(&mut out, session, &msg);
+                    true
+                }
+            },
+            _ = session::sleep_until(idle) => session.idle(),
+        };
+
+        notices(&mut out, session);
+        writer.write_all(out.as_bytes()).await?;
+        out.clear();
+
+        if !connected {
+            return Ok(());
+        }
+    }
+}
+
+/// Handle one line sent by the client. Returns `false` if the user quit.
+async fn handle_line(out: &mut String, session: &mut Session, line: &str) -> anyhow::Result<bool> {
+    let (command, params) = parse(line);
+    let nick = session.name.clone();
+    session.touch();
+
+    if !matches!(command.as_str(), "PING" | "PONG") {
+        match session.check_flood(line) {
+            Verdict::Accept => {}
+            Verdict::Reject(warning) => {
+                session.reply(warning);
+                return Ok(true);
+            }
+            Verdict::Disconnect(reason) => {
+                session.reply(reason);
+                return Ok(false);
+            }
+        }
+    }
+
+    match (command.as_str(), params.as_slice()) {
+        ("", _) | ("PONG", _) | ("CAP", _) | ("USER", _) => {}
+        ("PING", params) => pong(out, params),
+        ("NICK", [name, ..]) => {
+            let policy = session.state.config.names;
+            if policy.validate(name).is_err() {
+                numeric(out, "432", &nick, &format!("{name} :Erroneous nickname"));
+            } else if policy.is_taken(
+                name,
+                session
+                    .state
+                    .users
+                    .read()
+                    .await
+                    .keys()
+                    .filter(|user| **user != session.name)
+                    .map(|user| user.as_str()),
+            ) {
+                numeric(
+                    out,
+                    "433",
+                    &nick,
+                    &format!("{name} :Nickname is already in use"),
+                );
+            } else {
+                // the rename is announced in the room, and relayed back to us as NICK, the
+                // reply is only interesting when the name turned out to be unavailable
+                let old = session.name.clone();
+                run(session, &format!("nick {name}")).await?;
+                if session.name != old {
+                    session.take_output();
+                }
+            }
+        }
+        ("JOIN", [channels, ..]) => {
+            let channel = channels.split(',').next().unwrap_or_default();
+            match channel.strip_prefix('#') {
+                Some(room) if is_valid_room_name(room) => {
+                    if room != session.room.as_str() {
+                        parted(out, session);
+                        session.move_to(RoomName::new(room.to_string())).await;
+                        joined(out, session).await;
+                    }
+                }
+                _ => numeric(out, "403", &nick, &format!("{channel} :No such channel")),
+            }
+        }
+        ("PART", [channels, ..]) => {
+            let channel = channels.split(',').next().unwrap_or_default();
+            if !is_current(session, channel) {
+                numeric(
+                    out,
+                    "442",
+                    &nick,
+                    &format!("{channel} :You're not on that channel"),
+                );
+            } else {
+                let room = session.room.clone();
+                run(session, "leave").await?;
+                if session.room != room {
+                    out.push_str(&format!(":{} PART #{room}\r\n", prefix(&nick)));
+                    joined(out, session).await;
+                }
+            }
+        }
+        ("PRIVMSG", [target, text]) => {
+            if target.starts_with('#') {
+                if is_current(session, target) {
+                    session.say(text).await?;
+                } else {
+                    numeric(
+                        out,
+                        "404",
+                        &nick,
+                        &format!("{target} :Cannot send to channel"),
+                    );
+                }
+            } else {
+                privmsg(out, session, target, text).await;
+            }
+        }
+        ("NAMES", []) => names(out, session, &session.room.clone()).await,
+        ("NAMES", [channels, ..]) => {
+            for channel in channels.split(',') {
+                match channel.strip_prefix('#') {
+                    Some(room) => names(out, session, &RoomName::new(room.to_string())).await,
+                    None => numeric(out, "366", &nick, &format!("{channel} :End of /NAMES list")),
+                }
+            }
+        }
+        ("QUIT", _) => {
+            out.push_str("ERROR :Closing link\r\n");
+            return Ok(false);
+        }
+        ("NICK" | "JOIN" | "PART" | "PRIVMSG", _) => {
+            numeric(
+                out,
+                "461",
+                &nick,
+                &format!("{command} :Not enough parameters"),
+            );
+        }
+        (_, _) => numeric(out, "421", &nick, &format!("{command} :Unknown command")),
+    }
+
+    Ok(true)
+}
+
+/// Translate a message from the room, or a private message, into IRC.
+fn relay(out: &mut String, session: &Session, msg: &Msg) {
+    let from = prefix(&msg.from);
+    let room = &session.room;
+
+    match &msg.kind {
+        MsgKind::Rename(old) => out.push_str(&format!(":{} NICK {}\r\n", prefix(old), msg.from)),
+        _ if msg.from == session.name => {}
+        MsgKind::Arrival => out.push_str(&format!(":{from} JOIN #{room}\r\n")),
+        MsgKind::Departure => out.push_str(&format!(":{from} PART #{room}\r\n")),
+        MsgKind::Chat(text) => out.push_str(&format!(":{from} PRIVMSG #{room} :{text}\r\n")),
+        MsgKind::Direct { to, text } => out.push_str(&format!(":{from} PRIVMSG {to} :{text}\r\n")),
+        MsgKind::Notice => notice(out, &format!("#{room}"), msg.content.trim_end()),
+        MsgKind::Kick => {}
+    }
+}
+
+async fn privmsg(out: &mut String, session: &Session, to: &str, text: &str) {
+    let nick = &session.name;
+    let text = text::sanitize(text);
+
+    let Some(tx) = session
+        .state
+        .users
+        .read()
+        .await
+        .get(&to.to_string())
+        .map(|user| user.tx.clone())
+    else {
+        numeric(out, "401", nick, &format!("{to} :No such nick"));
+        return;
+    };
+
+    let msg = Msg {
+        from: nick.clone(),
+        peer: None,
+        content: Arc::new(format!("[{nick} -> {to}] {text}\n")),
+        kind: MsgKind::Direct {
+            to: Arc::new(to.to_string()),
+            text: Arc::new(text.to_string()),
+        },
+    };
+
+    match tx.try_send(msg) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => notice(
+            out,
+            nick,
+            &format!("* {to} is not keeping up, message not delivered"),
+        ),
+        Err(TrySendError::Closed(_)) => numeric(out, "401", nick, &format!("{to} :No such nick")),
+    }
+}
+
+/// Tell the client it is in the current room, with the users already there.
+async fn joined(out: &mut String, session: &mut Session) {
+    let room = session.room.clone();
+    out.push_str(&format!(":{} JOIN #{room}\r\n", prefix(&session.name)));
+    names(out, session, &room).await;
+}
+
+fn parted(out: &mut String, session: &Session) {
+    out.push_str(&format!(
+        ":{} PART #{}\r\n",
+        prefix(&session.name),
+        session.room
+    ));
+}
+
+async fn names(out: &mut String, session: &Session, room: &RoomName) {
+    let mut users = session.state.rooms.users(room).await;
+    users.sort();
+
+    let nick = &session.name;
+    if !users.is_empty() {
+        let users: Vec<_> = users.iter().map(|user| user.as_str()).collect();
+        numeric(out, "353", nick, &format!("= #{room} :{}", users.join(" ")));
+    }
+    numeric(out, "366", nick, &format!("#{room} :End of /NAMES list"));
+}
+
+/// Send everything the session wanted to tell the user as notices.
+fn notices(out: &mut String, session: &mut Session) {
+    let output = session.take_output();
+    for line in output.lines() {
+        notice(out, &session.name, line);
+    }
+}
+
+fn notice(out: &mut String, nick: &str, text: &str) {
+    out.push_str(&format!(":{SERVER} NOTICE {nick} :{text}\r\n"));
+}
+
+fn numeric(out: &mut String, code: &str, nick: &str, rest: &str) {
+    out.push_str(&format!(":{SERVER} {code} {nick} {rest}\r\n"));
+}
+
+fn pong(out: &mut String, params: &[&str]) {
+    let token = params.first().copied().unwrap_or(SERVER);
+    out.push_str(&format!(":{SERVER} PONG {SERVER} :{token}\r\n"));
+}
+
+fn prefix(name: &str) -> String {
+    format!("{name}!{name}@{SERVER}")
+}
+
+fn is_current(session: &Session, channel: &str) -> bool {
+    channel.strip_prefix('#') == Some(session.room.as_str())
+}
+
+async fn run(session: &mut Session, line: &str) -> anyhow::Result<()> {
+    let commands = session.state.commands.clone();
+    commands.run(session, line).await
+}
+
+/// Split a line into its upper-cased command and parameters, dropping the prefix.
+fn parse(line: &str) -> (String, Vec<&str>) {
+    let mut line = line.trim_end_matches(['\r', '\n']);
+    if line.starts_with(':') {
+        line = line
+            .split_once(' ')
+            .map(|(_, rest)| rest)
+            .unwrap_or_default();
+    }
+
+    let (line, trailing) = match line.split_once(" :") {
+        Some((line, trailing)) => (line, Some(trailing)),
+        None => (line, None),
+    };
+
+    let mut words = line.split(' ').filter(|word| !word.is_empty());
+    let command = words.next().unwrap_or_default().to_ascii_uppercase();
+    let mut params: Vec<_> = words.collect();
+    params.extend(trailing);
+
+    (command, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncBufReadExt, Lines},
+        net::{
+            tcp::{OwnedReadHalf, OwnedWriteHalf},
+            TcpListener,
+        },
+    };
+
+    use super::*;
+    use crate::tests::state;
+
+    /// Serve IRC clients on a loopback port.
+    async fn serve(state: State) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = listener.accept().await.unwrap();
+                tokio::spawn(handle_stream(stream, addr, state.clone()));
+            }
+        });
+        addr
+    }
+
+    struct Client {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr) -> Self {
+            let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+            Self {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            }
+        }
+
+        /// Connect and register as `nick`.
+        async fn register(addr: SocketAddr, nick: &str) -> Self {
+            let mut client = Self::connect(addr).await;
+            client.send(&format!("NICK {nick}")).await;
+            client.send(&format!("USER {nick} 0 * :{nick}")).await;
+            client.expect(" 001 ").await;
+            client
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.writer
+                .write_all(format!("{line}\r\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        /// Skip lines until one containing `expected`, and return it.
+        async fn expect(&mut self, expected: &str) -> String {
+            let next = async {
+                loop {
+                    match self.lines.next_line().await.unwrap() {
+                        Some(line) if line.contains(expected) => return line,
+                        Some(_) => {}
+                        None => panic!("connection closed, expected {expected:?}"),
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), next)
+                .await
+                .unwrap_or_else(|_| panic!("timed out, expected {expected:?}"))
+        }
+
+        /// Wait for the server to close the connection.
+        async fn expect_closed(&mut self) {
+            let closed = async { while self.lines.next_line().await.unwrap().is_some() {} };
+            tokio::time::timeout(Duration::from_secs(5), closed)
+                .await
+                .expect("connection still open");
+        }
+    }
+
+    #[tokio::test]
+    async fn join_privmsg_quit() {
+        let state = state(&[]);
+        let addr = serve(state.clone()).await;
+
+        let mut alice = Client::register(addr, "alice").await;
+        alice.send("JOIN #room").await;
+        alice.expect(":alice!alice@budgetchat JOIN #room").await;
+        alice.expect(" 353 alice = #room :alice").await;
+
+        let mut bob = Client::register(addr, "bob").await;
+        bob.send("JOIN #room").await;
+        bob.expect(" 353 bob = #room :alice bob").await;
+        alice.expect(":bob!bob@budgetchat JOIN #room").await;
+
+        bob.send("PRIVMSG #room :hello there").await;
+        alice
+            .expect(":bob!bob@budgetchat PRIVMSG #room :hello there")
+            .await;
+
+        alice.send("PRIVMSG bob :psst").await;
+        bob.expect(":alice!alice@budgetchat PRIVMSG bob :psst")
+            .await;
+
+        bob.send("QUIT :bye").await;
+        bob.expect("ERROR :Closing link").await;
+        bob.expect_closed().await;
+        alice.expect(":bob!bob@budgetchat PART #room").await;
+
+        assert!(!state.users.read().await.contains_key(&"bob".to_string()));
+    }
+
+    #[tokio::test]
+    async fn nick_collision() {
+        let state = state(&[]);
+        let addr = serve(state.clone()).await;
+        let _alice = Client::register(addr, "alice").await;
+
+        let mut other = Client::connect(addr).await;
+        other.send("NICK alice").await;
+        other.send("USER alice 0 * :alice").await;
+        other
+            .expect(" 433 * alice :Nickname is already in use")
+            .await;
+
+        // USER was already sent, another NICK is enough
+        other.send("NICK alice2").await;
+        other.expect(" 001 alice2 ").await;
+
+        // and once registered, renaming into a taken name fails too
+        other.send("NICK alice").await;
+        other
+            .expect(" 433 alice2 alice :Nickname is already in use")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn registration_timeout() {
+        let state = state(&["--name-timeout-secs=1"]);
+        let addr = serve(state.clone()).await;
+
+        let mut client = Client::connect(addr).await;
+        client.send("NICK slow").await;
+        client.expect("ERROR :Registration timeout").await;
+        client.expect_closed().await;
+        assert!(state.users.read().await.is_empty());
+
+        // the timeout only covers registering
+        let mut client = Client::register(addr, "quick").await;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        client.send("PING :still").await;
+        client.expect("PONG budgetchat :still").await;
+    }
+}
@@ -0,0 +1,91 @@
+//! Messages sent while a user chooses their name, in the language chosen with `--lang=<lang>`.
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+use crate::text::{Rejection, MAX_NAME_LEN};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Lang {
+    #[default]
+    En,
+    De,
+    Fr,
+}
+
+impl FromStr for Lang {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "en" => Ok(Lang::En),
+            "de" => Ok(Lang::De),
+            "fr" => Ok(Lang::Fr),
+            s => Err(anyhow!("unknown language {s:?}, expected en, de or fr")),
+        }
+    }
+}
+
+impl Lang {
+    pub fn welcome(self) -> &'static str {
+        match self {
+            Self::En => "* Welcome to budgetchat! What shall I call you?\n",
+            Self::De => "* Willkommen bei budgetchat! Wie soll ich dich nennen?\n",
+            Self::Fr => "* Bienvenue sur budgetchat ! Comment dois-je t'appeler ?\n",
+        }
+    }
+
+    pub fn rejection(self, rejection: Rejection) -> String {
+        match (self, rejection) {
+            (Self::En, Rejection::Empty) => "* a name is required\n".to_string(),
+            (Self::En, Rejection::InvalidChars) => {
+                "* names may only contain letters and digits\n".to_string()
+            }
+            (Self::En, Rejection::TooLong) => {
+                format!("* name is too long, at most {MAX_NAME_LEN} characters allowed\n")
+            }
+            (Self::En, Rejection::Duplicate) => "* this name is already in use\n".to_string(),
+            (Self::En, Rejection::Banned) => "* this name is banned\n".to_string(),
+
+            (Self::De, Rejection::Empty) => "* ein Name ist erforderlich\n".to_string(),
+            (Self::De, Rejection::InvalidChars) => {
+                "* Namen dürfen nur Buchstaben und Ziffern enthalten\n".to_string()
+            }
+            (Self::De, Rejection::TooLong) => {
+                format!("* der Name ist zu lang, höchstens {MAX_NAME_LEN} Zeichen erlaubt\n")
+            }
+            (Self::De, Rejection::Duplicate) => {
+                "* dieser Name wird bereits verwendet\n".to_string()
+            }
+            (Self::De, Rejection::Banned) => "* dieser Name ist gesperrt\n".to_string(),
+
+            (Self::Fr, Rejection::Empty) => "* un nom est requis\n".to_string(),
+            (Self::Fr, Rejection::InvalidChars) => {
+                "* un nom ne peut contenir que des lettres et des chiffres\n".to_string()
+            }
+            (Self::Fr, Rejection::TooLong) => {
+                format!("* ce nom est trop long, {MAX_NAME_LEN} caractères au maximum\n")
+            }
+            (Self::Fr, Rejection::Duplicate) => "* ce nom est déjà utilisé\n".to_string(),
+            (Self::Fr, Rejection::Banned) => "* ce nom est banni\n".to_string(),
+        }
+    }
+
+    /// Sent after a rejection, when the user may try again.
+    pub fn retry(self) -> &'static str {
+        match self {
+            Self::En => "* please choose another name\n",
+            Self::De => "* bitte wähle einen anderen Namen\n",
+            Self::Fr => "* merci de choisir un autre nom\n",
+        }
+    }
+
+    pub fn name_timeout(self) -> &'static str {
+        match self {
+            Self::En => "* took too long to choose a name\n",
+            Self::De => "* die Namenswahl hat zu lange gedauert\n",
+            Self::Fr => "* le choix du nom a pris trop de temps\n",
+        }
+    }
+}
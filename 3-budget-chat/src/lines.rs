@@ -0,0 +1,124 @@
+//! Reading lines without buffering more than `--max-len` allows.
+//!
+//! [`tokio::io::Lines`] keeps reading until the end of the line, however long it gets, so a client
+//! never sending a newline could make the server buffer without bound before [`crate::flood`] ever
+//! sees the line. [`BoundedLines`] gives up as soon as a line can't be short enough anymore.
+
+use std::io;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Most bytes a character takes in UTF-8.
+const MAX_CHAR_LEN: usize = 4;
+
+/// Longest line in bytes, including the line ending, which can still have `max_len` characters.
+pub fn max_bytes(max_len: usize) -> usize {
+    max_len * MAX_CHAR_LEN + "\r\n".len()
+}
+
+/// Like [`tokio::io::Lines`], but failing with [`io::ErrorKind::InvalidData`] once a line grows
+/// longer than [`max_bytes`] of `max_len`, if there is a `max_len`.
+pub struct BoundedLines<R> {
+    reader: R,
+    max_bytes: Option<usize>,
+    /// The line read so far, kept here so [`Self::next_line`] can be cancelled.
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncBufRead + Unpin> BoundedLines<R> {
+    pub fn new(reader: R, max_len: Option<usize>) -> Self {
+        Self {
+            reader,
+            max_bytes: max_len.map(max_bytes),
+            buf: Vec::new(),
+        }
+    }
+
+    /// The next line without its line ending, or `None` at the end of the stream.
+    ///
+    /// Cancel safe, nothing read is lost if the future is dropped.
+    pub async fn next_line(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return self.take_line().map(Some);
+            }
+
+            let (used, complete) = match available.iter().position(|b| *b == b'\n') {
+                Some(end) => (end + 1, true),
+                None => (available.len(), false),
+            };
+            self.buf.extend_from_slice(&available[..used]);
+            self.reader.consume(used);
+
+            if let Some(max_bytes) = self.max_bytes {
+                if self.buf.len() > max_bytes {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line longer than {max_bytes} bytes"),
+                    ));
+                }
+            }
+            if complete {
+                return self.take_line().map(Some);
+            }
+        }
+    }
+
+    fn take_line(&mut self) -> io::Result<String> {
+        let mut line = std::mem::take(&mut self.buf);
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn splits_like_lines() {
+        let mut lines = BoundedLines::new(&b"one\ntwo\r\n\nlast"[..], None);
+        for expected in ["one", "two", "", "last"] {
+            assert_eq!(lines.next_line().await.unwrap().as_deref(), Some(expected));
+        }
+        assert_eq!(lines.next_line().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn accepts_longest_line() {
+        let line = "é".repeat(1000) + "\r\n";
+        let mut lines = BoundedLines::new(line.as_bytes(), Some(1000));
+        assert_eq!(lines.next_line().await.unwrap(), Some("é".repeat(1000)));
+    }
+
+    #[tokio::test]
+    async fn fails_once_too_long() {
+        // no line ending, the read has to stop anyway
+        let input = vec![b'a'; max_bytes(1000) + 1];
+        let mut lines = BoundedLines::new(&input[..], Some(1000));
+        let e = lines.next_line().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn unbounded_without_max_len() {
+        let input = "a".repeat(100_000) + "\n";
+        let mut lines = BoundedLines::new(input.as_bytes(), None);
+        assert_eq!(lines.next_line().await.unwrap().unwrap().len(), 100_000);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_utf8() {
+        let mut lines = BoundedLines::new(&b"\xff\n"[..], None);
+        assert!(lines.next_line().await.is_err());
+    }
+}
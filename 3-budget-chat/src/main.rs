@@ -1,172 +1,384 @@
-use std::{collections::HashSet, env, net::SocketAddr, sync::Arc};
+mod bot;
+mod command;
+mod config;
+mod events;
+mod federation;
+mod flood;
+mod history;
+mod irc;
+mod lang;
+mod lines;
+mod moderation;
+mod room;
+mod session;
+mod text;
+mod transcript;
+mod ws;
+
+use std::{
+    collections::HashMap, future::Future, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration,
+};
 
 use anyhow::anyhow;
-use futures::{stream::FuturesUnordered, StreamExt};
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
-    sync::{broadcast, RwLock},
+    sync::{mpsc, RwLock},
 };
 use tracing::{error, info};
 
+use command::Commands;
+use config::Config;
+use events::Events;
+use federation::Federation;
+use history::History;
+use lines::BoundedLines;
+use moderation::Moderation;
+use room::{RoomName, Rooms};
+use session::Session;
+use text::Rejection;
+use transcript::Transcript;
 use util::{log_and_exit, write_and_exit};
 
 pub type UserName = Arc<String>;
 pub type MsgConent = Arc<String>;
-pub type UsersList = Arc<RwLock<HashSet<Arc<String>>>>;
+/// Every joined user.
+pub type UsersList = Arc<RwLock<HashMap<UserName, User>>>;
+
+#[derive(Clone)]
+pub struct User {
+    /// Sender for private messages.
+    pub tx: mpsc::Sender<Msg>,
+    /// Where the user is connected from, `None` for bots.
+    pub peer: Option<SocketAddr>,
+}
 
-const WELCOME_MESSAGE: &[u8] = b"* Welcome to budgetchat! What shall I call you?\n";
-const LONG_NAME_ERR_MESSAGE: &[u8] = b"* name is to long, atmost 16 characters allowed\n";
-const DUPLICATE_NAME_ERR_MESSAGE: &[u8] = b"* this name is already in use\n";
+#[derive(Clone)]
+pub struct State {
+    pub rooms: Rooms,
+    pub users: UsersList,
+    pub commands: Arc<Commands>,
+    pub config: Arc<Config>,
+    pub moderation: Arc<Moderation>,
+    pub federation: Arc<Federation>,
+    pub events: Arc<Events>,
+}
 
 #[derive(Debug, Clone)]
 pub struct Msg {
     from: UserName,
+    /// Where `from` is connected from, on arrivals, departures and chat messages of local users.
+    peer: Option<SocketAddr>,
+    /// Ready to be sent to TCP clients.
     content: MsgConent,
+    kind: MsgKind,
 }
 
-fn create_arrival_message(from: UserName) -> Msg {
+/// What a [`Msg`] is about, for frontends which can't relay `content` as is.
+#[derive(Debug, Clone)]
+pub enum MsgKind {
+    Arrival,
+    Departure,
+    Chat(MsgConent),
+    /// `from` was previously known by this name.
+    Rename(UserName),
+    Direct {
+        to: UserName,
+        text: MsgConent,
+    },
+    /// Sent by the server, to everyone.
+    Notice,
+    /// Sent directly to a user who has to be disconnected.
+    Kick,
+}
+
+fn create_arrival_message(from: UserName, peer: Option<SocketAddr>) -> Msg {
     Msg {
         content: MsgConent::new(format!("* {from} has entered the room\n")),
         from,
+        peer,
+        kind: MsgKind::Arrival,
     }
 }
 
-fn create_departure_message(from: UserName) -> Msg {
+fn create_departure_message(from: UserName, peer: Option<SocketAddr>) -> Msg {
     Msg {
         content: MsgConent::new(format!("* {from} has left the room\n")),
         from,
+        peer,
+        kind: MsgKind::Departure,
     }
 }
 
 fn create_current_users_message(users: &[UserName]) -> String {
-    if users.len() == 0 {
+    if users.is_empty() {
         return "* no users in room\n".to_string();
     }
 
     let mut res = format!("* the room contains: {}", users[0]);
-    for user in users.into_iter().skip(1) {
+    for user in users.iter().skip(1) {
         res.push_str(", ");
-        res.push_str(&user);
+        res.push_str(user);
     }
 
     res.push('\n');
 
-    return res;
+    res
 }
 
-async fn handle_stream(
-    mut stream: TcpStream,
-    addr: SocketAddr,
-    tx: broadcast::Sender<Msg>,
-    users: UsersList,
-) -> anyhow::Result<()> {
-    let (reader, mut writer) = stream.split();
-    let mut lines = BufReader::new(reader).lines();
-
-    // greet the user
+fn create_rooms_message(rooms: &[(RoomName, usize)]) -> String {
+    let mut res = "* rooms:".to_string();
+    for (room, users) in rooms {
+        res.push_str(&format!(" {room} ({users})"));
+    }
+    res.push('\n');
+    res
+}
 
-    writer.write_all(WELCOME_MESSAGE).await?;
+/// Run `future` to completion, or return `None` if it takes longer than `timeout`.
+async fn with_timeout<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.ok(),
+        None => Some(future.await),
+    }
+}
 
-    // get the username
+/// Validate `name` and let the user connected from `peer` in, or return why they can't join.
+async fn admit(state: State, name: String, peer: Option<SocketAddr>) -> Result<Session, Rejection> {
+    let (name, direct_rx) = match reserve_name(&state, &name, peer).await {
+        Ok(reserved) => reserved,
+        Err(rejection) => {
+            state.events.rejected_join(&name, peer, rejection);
+            return Err(rejection);
+        }
+    };
 
-    let name = lines
-        .next_line()
-        .await?
-        .ok_or_else(|| anyhow!("no userame given\n"))?;
+    Ok(Session::start(state, name, peer, direct_rx).await)
+}
 
-    // validate the username
+/// Check that `name` can be used and take it for the user connected from `peer`, returning it as
+/// it is shown with the receiver of private messages, or why it can't be used.
+async fn reserve_name(
+    state: &State,
+    name: &str,
+    peer: Option<SocketAddr>,
+) -> Result<(UserName, mpsc::Receiver<Msg>), Rejection> {
+    let policy = state.config.names;
+    let name = policy.validate(name)?;
+
+    if state.moderation.is_banned_name(policy, &name).await {
+        return Err(Rejection::Banned);
+    }
 
-    if name.is_empty() || name.chars().any(|c| !c.is_alphanumeric()) || name.len() > 16 {
-        write_and_exit!(writer, LONG_NAME_ERR_MESSAGE, addr);
+    // checking and inserting under the same lock, so two users can't both get the name
+    let mut users = state.users.write().await;
+    let taken = policy.is_taken(&name, users.keys().map(|user| user.as_str()))
+        || state.federation.is_remote(policy, &name).await;
+    if taken {
+        return Err(Rejection::Duplicate);
     }
 
     let name = Arc::new(name);
+    let (direct_tx, direct_rx) = mpsc::channel(64);
+    users.insert(
+        name.clone(),
+        User {
+            tx: direct_tx,
+            peer,
+        },
+    );
+
+    Ok((name, direct_rx))
+}
+
+async fn handle_stream(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    state: State,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut lines = BoundedLines::new(BufReader::new(reader), state.config.flood.max_len);
 
-    let current_users = {
-        let lock = users.read().await;
+    // greet the user
 
-        if lock.contains(&name) {
-            write_and_exit!(writer, DUPLICATE_NAME_ERR_MESSAGE, addr);
+    let lang = state.config.lang;
+    writer.write_all(lang.welcome().as_bytes()).await?;
+
+    // get the username, validate and reserve it, make presence noticed in the default room
+
+    let mut retries = state.config.name_retries;
+    let mut session = loop {
+        let Some(name) = with_timeout(state.config.name_timeout, lines.next_line()).await else {
+            let msg = lang.name_timeout().as_bytes();
+            write_and_exit!(writer, msg, addr);
+        };
+        let name = name?.ok_or_else(|| anyhow!("no userame given\n"))?;
+
+        match admit(state.clone(), name, Some(addr)).await {
+            Ok(session) => break session,
+            Err(rejection) => {
+                writer
+                    .write_all(lang.rejection(rejection).as_bytes())
+                    .await?;
+                if retries == 0 {
+                    log_and_exit!(addr);
+                }
+                retries -= 1;
+                writer.write_all(lang.retry().as_bytes()).await?;
+            }
         }
-
-        lock.iter().map(Arc::clone).collect::<Vec<_>>()
     };
 
-    // make presence noticed, append to UsersList
+    // send back list of all current users, then chat messages
 
-    {
-        let lock = &mut users.write().await;
-        let arrival_message = create_arrival_message(name.clone());
-        tx.send(arrival_message)?;
-        lock.insert(name.clone());
-    }
+    let res = chat(&mut lines, &mut writer, &mut session).await;
+
+    // make absence noticed, remove from UsersList, however the chat ended
 
-    // send back list of all current users
+    session.finish().await;
 
-    writer
-        .write_all(create_current_users_message(&current_users).as_bytes())
-        .await?;
+    res?;
+    log_and_exit!(addr);
+}
 
-    // chat messages
+async fn chat<R, W>(
+    lines: &mut BoundedLines<R>,
+    writer: &mut W,
+    session: &mut Session,
+) -> anyhow::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // what joining produced, written here so a failed write still ends with session.finish()
+    writer.write_all(session.take_output().as_bytes()).await?;
 
-    let mut rx = tx.subscribe();
     loop {
-        tokio::select! {
+        let idle = session.idle_deadline();
+
+        let connected = tokio::select! {
             res_msg_opt = lines.next_line() => {
-                let msg = match res_msg_opt? {
-                    Some(msg) =>  Arc::new(format!("[{name}] {}\n", msg.trim())),
-                    None => break,
+                let Some(line) = res_msg_opt? else {
+                    return Ok(());
                 };
-                tx.send(Msg { from: name.clone() , content: msg })?;
-            }
-            res_msg = rx.recv() => {
-                let msg = res_msg?;
-                if msg.from != name {
-                    writer.write_all(msg.content.as_bytes()).await?;
-                }
+                session.handle_line(line.trim()).await?
             }
+            res_msg = session.joined.rx.recv() => session.receive(res_msg),
+            Some(msg) = session.direct_rx.recv() => session.receive_direct(msg),
+            _ = session::sleep_until(idle) => session.idle(),
+        };
+
+        writer.write_all(session.take_output().as_bytes()).await?;
+
+        if !connected {
+            return Ok(());
         }
     }
+}
 
-    // make absence notices, remove from UsersList
-
-    {
-        let lock = &mut users.write().await;
-        let departure_message = create_departure_message(name.clone());
-        tx.send(departure_message.clone())?;
-        lock.remove(&name);
+async fn bind(addr: Option<SocketAddr>) -> std::io::Result<Option<TcpListener>> {
+    match addr {
+        Some(addr) => TcpListener::bind(addr).await.map(Some),
+        None => Ok(None),
     }
+}
 
-    log_and_exit!(addr);
+/// Accept on an optional listener, never resolving without one.
+async fn accept(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt().init();
 
-    let mut args = env::args();
-    args.next().expect("no binary name");
+    let args = util::Args::from_env();
+
+    if let Some(dir) = args.value::<PathBuf>("archive")? {
+        return transcript::run(&dir, &args);
+    }
+
+    let addr = args.addr()?;
 
-    let addr: SocketAddr = args.next().expect("no addr").parse()?;
+    let config = Config::from_args(&args)?;
+    let history = History::open(config.history_size, config.history_file.as_deref())?;
+    let transcript = match &config.transcript_dir {
+        Some(dir) => Transcript::open(dir, config.transcript_max_bytes)?,
+        None => Transcript::disabled(),
+    };
+    let moderation = Moderation::open(&config)?;
+    let federation = Federation::new(config.server_name.clone());
+    let events = Arc::new(Events::open(&config).await?);
 
     let server = TcpListener::bind(addr).await?;
+    let ws_server = bind(config.ws).await?;
+    let irc_server = bind(config.irc).await?;
+    let federation_server = bind(config.federation).await?;
 
     let mut connections = FuturesUnordered::new();
-    let (tx, _rx) = broadcast::channel(1024);
-    let users = UsersList::new(RwLock::new(HashSet::new()));
+
+    let state = State {
+        rooms: Rooms::new(history, transcript, events.clone()),
+        users: UsersList::default(),
+        commands: Arc::new(Commands::builtin()),
+        config: Arc::new(config),
+        moderation: Arc::new(moderation),
+        federation: Arc::new(federation),
+        events,
+    };
+
+    if let Some(listener) = federation_server {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = federation::listen(listener, state).await {
+                error!("federation listener failed: {e}");
+            }
+        });
+    }
+    for &addr in &state.config.peers {
+        tokio::spawn(federation::connect(addr, state.clone()));
+    }
+
+    for bot in bot::from_config(&state.config)? {
+        bot::start(state.clone(), bot).await?;
+    }
 
     loop {
         tokio::select! {
             res = server.accept() => {
                 let (stream, addr) = res?;
+                if state.moderation.is_banned_ip(addr.ip()).await {
+                    info!("refused connection from banned {addr}");
+                    continue;
+                }
                 info!("accepted connection from {addr}");
                 connections.push(handle_stream(
                     stream,
                     addr,
-                    tx.clone(),
-                    users.clone()
-                ));
+                    state.clone(),
+                ).boxed());
+            }
+            res = accept(&ws_server) => {
+                let (stream, addr) = res?;
+                if state.moderation.is_banned_ip(addr.ip()).await {
+                    info!("refused websocket connection from banned {addr}");
+                    continue;
+                }
+                info!("accepted websocket connection from {addr}");
+                connections.push(ws::handle_stream(stream, addr, state.clone()).boxed());
+            }
+            res = accept(&irc_server) => {
+                let (stream, addr) = res?;
+                if state.moderation.is_banned_ip(addr.ip()).await {
+                    info!("refused irc connection from banned {addr}");
+                    continue;
+                }
+                info!("accepted irc connection from {addr}");
+                connections.push(irc::handle_stream(stream, addr, state.clone()).boxed());
             }
             opt_res = connections.next() => {
                 if let Some(Err(e)) = opt_res {
@@ -179,3 +391,223 @@ async fn main() -> anyhow::Result<()> {
     #[allow(unreachable_code)]
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use lang::Lang;
+    use moderation::Target;
+
+    use super::*;
+
+    /// A server configured with `args`, without any files or listeners.
+    pub(crate) fn state(args: &[&str]) -> State {
+        let args = util::Args::parse(args.iter().map(|arg| arg.to_string()));
+        let config = Config::from_args(&args).unwrap();
+        let history = History::open(config.history_size, None).unwrap();
+        let events = Arc::new(Events::disabled());
+
+        State {
+            rooms: Rooms::new(history, Transcript::disabled(), events.clone()),
+            users: UsersList::default(),
+            commands: Arc::new(Commands::builtin()),
+            moderation: Arc::new(Moderation::open(&config).unwrap()),
+            federation: Arc::new(Federation::new(config.server_name.clone())),
+            config: Arc::new(config),
+            events,
+        }
+    }
+
+    /// Serve TCP clients on a loopback port.
+    pub(crate) async fn serve(state: State) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = listener.accept().await.unwrap();
+                tokio::spawn(handle_stream(stream, addr, state.clone()));
+            }
+        });
+        addr
+    }
+
+    /// Connect, send every one of `names` as it is asked for, and return everything the server
+    /// sent until it closed the connection.
+    async fn choose_names(addr: SocketAddr, names: &[&str]) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for name in names {
+            stream
+                .write_all(format!("{name}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        let mut received = String::new();
+        let read = tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut received);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("connection still open")
+            .unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_names() {
+        let lang = Lang::En;
+        let addr = serve(state(&[])).await;
+
+        let too_long = "a".repeat(text::MAX_NAME_LEN + 1);
+        let cases = [
+            ("", Rejection::Empty),
+            ("al ice", Rejection::InvalidChars),
+            (&too_long, Rejection::TooLong),
+        ];
+        for (name, rejection) in cases {
+            assert_eq!(
+                choose_names(addr, &[name]).await,
+                lang.welcome().to_string() + &lang.rejection(rejection),
+                "{name:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_duplicate_names() {
+        let lang = Lang::En;
+        let state = state(&[]);
+        let addr = serve(state.clone()).await;
+        let _alice = admit(state, "alice".to_string(), None).await.unwrap();
+
+        assert_eq!(
+            choose_names(addr, &["alice"]).await,
+            lang.welcome().to_string() + &lang.rejection(Rejection::Duplicate)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_banned_names() {
+        let lang = Lang::En;
+        let state = state(&[]);
+        let addr = serve(state.clone()).await;
+        let target = Target::Name("mallory".to_string());
+        state.moderation.ban(&target).await.unwrap();
+
+        assert_eq!(
+            choose_names(addr, &["mallory"]).await,
+            lang.welcome().to_string() + &lang.rejection(Rejection::Banned)
+        );
+        assert!(state.users.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejections_are_localized() {
+        let lang = Lang::De;
+        let addr = serve(state(&["--lang=de"])).await;
+
+        assert_eq!(
+            choose_names(addr, &["al ice"]).await,
+            lang.welcome().to_string() + &lang.rejection(Rejection::InvalidChars)
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_until_exhausted() {
+        let lang = Lang::En;
+        let addr = serve(state(&["--name-retries=2"])).await;
+
+        let expected = [
+            lang.welcome(),
+            &lang.rejection(Rejection::Empty),
+            lang.retry(),
+            &lang.rejection(Rejection::InvalidChars),
+            lang.retry(),
+            &lang.rejection(Rejection::InvalidChars),
+        ]
+        .concat();
+        assert_eq!(choose_names(addr, &["", "a b", "c d"]).await, expected);
+    }
+
+    #[tokio::test]
+    async fn retry_can_succeed() {
+        let lang = Lang::En;
+        let state = state(&["--name-retries=1"]);
+        let addr = serve(state.clone()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"a b\nalice\n").await.unwrap();
+        let expected = [
+            lang.welcome(),
+            &lang.rejection(Rejection::InvalidChars),
+            lang.retry(),
+            "* no users in room\n",
+        ]
+        .concat();
+        let mut received = vec![0; expected.len()];
+        tokio::io::AsyncReadExt::read_exact(&mut stream, &mut received)
+            .await
+            .unwrap();
+        assert_eq!(String::from_utf8(received).unwrap(), expected);
+        assert!(state.users.read().await.contains_key(&"alice".to_string()));
+    }
+
+    #[tokio::test]
+    async fn times_out_choosing_a_name() {
+        let lang = Lang::En;
+        let state = state(&["--name-timeout-secs=1"]);
+        let addr = serve(state.clone()).await;
+
+        assert_eq!(
+            choose_names(addr, &[]).await,
+            lang.welcome().to_string() + lang.name_timeout()
+        );
+        assert!(state.users.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn warns_once_before_idle_timeout() {
+        let addr = serve(state(&["--idle-timeout-secs=2"])).await;
+
+        // the default warning of 30 seconds doesn't fit, so it comes half way through
+        let output = choose_names(addr, &["alice"]).await;
+        assert_eq!(output.matches("* you are idle").count(), 1, "{output}");
+        assert!(output.contains("disconnected in 1 seconds"), "{output}");
+        assert!(output.ends_with("* disconnecting, you were idle for too long\n"));
+    }
+
+    #[test]
+    fn idle_warning_has_to_be_shorter_than_timeout() {
+        let args = ["--idle-timeout-secs=30", "--idle-warning-secs=30"];
+        let args = util::Args::parse(args.iter().map(|arg| arg.to_string()));
+        assert!(Config::from_args(&args).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_joins_with_one_name() {
+        let state = state(&["--names=unicode"]);
+
+        // the same name, and names which can't be told apart from it
+        let names = ["alice", "\u{430}lice", "alic\u{435}"];
+        let joins: Vec<_> = (0..60)
+            .map(|i| {
+                let name = names[i % names.len()].to_string();
+                tokio::spawn(admit(state.clone(), name, None))
+            })
+            .collect();
+
+        let mut admitted = Vec::new();
+        for join in joins {
+            match join.await.unwrap() {
+                Ok(session) => admitted.push(session),
+                Err(rejection) => assert_eq!(rejection, Rejection::Duplicate),
+            }
+        }
+        assert_eq!(admitted.len(), 1);
+        assert_eq!(state.users.read().await.len(), 1);
+
+        // the room agrees with the list of users
+        let room = RoomName::new(room::DEFAULT_ROOM.to_string());
+        assert_eq!(
+            state.rooms.users(&room).await,
+            vec![admitted[0].name.clone()]
+        );
+    }
+}
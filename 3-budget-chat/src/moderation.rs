@@ -0,0 +1,161 @@
+//! Operators, and the bans and mutes they hand out.
+//!
+//! Users become operators by joining with a name listed in `--operators`, or with
+//! `/oper <password>` if `--oper-password` is set. Bans are kept in `--bans-file` as `name <name>`
+//! and `ip <ip>` lines, so they survive restarts, mutes only last as long as the server runs.
+//! A name ban also covers every name which can't be told apart from it, see
+//! [`NamePolicy::is_taken`].
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use tokio::sync::RwLock;
+
+use crate::{config::Config, text::NamePolicy, UserName};
+
+#[derive(Default)]
+struct Bans {
+    names: BTreeSet<String>,
+    ips: BTreeSet<IpAddr>,
+}
+
+pub enum Target {
+    Name(String),
+    Ip(IpAddr),
+}
+
+impl Target {
+    pub fn parse(arg: &str) -> Self {
+        match arg.parse() {
+            Ok(ip) => Self::Ip(ip),
+            Err(_) => Self::Name(arg.to_string()),
+        }
+    }
+}
+
+pub struct Moderation {
+    operators: HashSet<String>,
+    password: Option<String>,
+    file: Option<PathBuf>,
+    bans: RwLock<Bans>,
+    muted: RwLock<HashSet<UserName>>,
+}
+
+impl Moderation {
+    pub fn open(config: &Config) -> anyhow::Result<Self> {
+        let mut bans = Bans::default();
+
+        if let Some(path) = config.bans_file.as_deref().filter(|path| path.exists()) {
+            let content = fs::read_to_string(path)?;
+            for line in content.lines() {
+                match line.split_once(' ') {
+                    Some(("name", name)) => {
+                        bans.names.insert(name.to_string());
+                    }
+                    Some(("ip", ip)) => {
+                        let ip = ip
+                            .parse()
+                            .with_context(|| format!("invalid ip in {}", path.display()))?;
+                        bans.ips.insert(ip);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(Self {
+            operators: config.operators.iter().cloned().collect(),
+            password: config.oper_password.clone(),
+            file: config.bans_file.clone(),
+            bans: RwLock::new(bans),
+            muted: RwLock::default(),
+        })
+    }
+
+    pub fn is_operator(&self, name: &str) -> bool {
+        self.operators.contains(name)
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        self.password.as_deref() == Some(password)
+    }
+
+    /// Whether `name` can't be told apart from a banned name, according to `policy`.
+    pub async fn is_banned_name(&self, policy: NamePolicy, name: &str) -> bool {
+        let bans = self.bans.read().await;
+        policy.is_taken(name, bans.names.iter().map(String::as_str))
+    }
+
+    pub async fn is_banned_ip(&self, ip: IpAddr) -> bool {
+        self.bans.read().await.ips.contains(&ip)
+    }
+
+    /// Returns `false` if `target` was already banned.
+    pub async fn ban(&self, target: &Target) -> anyhow::Result<bool> {
+        let mut bans = self.bans.write().await;
+        let added = match target {
+            Target::Name(name) => bans.names.insert(name.clone()),
+            Target::Ip(ip) => bans.ips.insert(*ip),
+        };
+        if added {
+            self.persist(&bans)?;
+        }
+        Ok(added)
+    }
+
+    /// Returns `false` if `target` was not banned.
+    pub async fn unban(&self, target: &Target) -> anyhow::Result<bool> {
+        let mut bans = self.bans.write().await;
+        let removed = match target {
+            Target::Name(name) => bans.names.remove(name),
+            Target::Ip(ip) => bans.ips.remove(ip),
+        };
+        if removed {
+            self.persist(&bans)?;
+        }
+        Ok(removed)
+    }
+
+    pub async fn is_muted(&self, name: &UserName) -> bool {
+        self.muted.read().await.contains(name)
+    }
+
+    /// Returns `false` if `name` was already muted.
+    pub async fn mute(&self, name: UserName) -> bool {
+        self.muted.write().await.insert(name)
+    }
+
+    /// Returns `false` if `name` was not muted.
+    pub async fn unmute(&self, name: &UserName) -> bool {
+        self.muted.write().await.remove(name)
+    }
+
+    fn persist(&self, bans: &Bans) -> anyhow::Result<()> {
+        let Some(path) = &self.file else {
+            return Ok(());
+        };
+
+        let mut content = String::new();
+        for name in &bans.names {
+            content.push_str(&format!("name {name}\n"));
+        }
+        for ip in &bans.ips {
+            content.push_str(&format!("ip {ip}\n"));
+        }
+
+        write_atomically(path, &content)
+    }
+}
+
+/// Write to a temporary file first, so a crash never leaves a half written ban list behind.
+fn write_atomically(path: &Path, content: &str) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use tokio::sync::{broadcast, RwLock};
+
+use crate::{
+    create_arrival_message, create_departure_message,
+    events::Events,
+    history::History,
+    transcript::{Kind, Transcript},
+    Msg, MsgConent, MsgKind, UserName,
+};
+
+pub type RoomName = Arc<String>;
+
+/// Room every user is placed in after joining, and returns to with `/leave`.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+pub struct Room {
+    tx: broadcast::Sender<Msg>,
+    users: HashSet<UserName>,
+}
+
+impl Room {
+    fn new(name: RoomName, events: &Events) -> Self {
+        let (tx, _rx) = broadcast::channel(1024);
+        events.watch(name, tx.subscribe());
+        Self {
+            tx,
+            users: HashSet::new(),
+        }
+    }
+}
+
+struct RoomsInner {
+    rooms: HashMap<RoomName, Room>,
+    history: History,
+    transcript: Transcript,
+    events: Arc<Events>,
+}
+
+#[derive(Clone)]
+pub struct Rooms(Arc<RwLock<RoomsInner>>);
+
+pub struct Joined {
+    pub tx: broadcast::Sender<Msg>,
+    pub rx: broadcast::Receiver<Msg>,
+    /// Users who were already in the room.
+    pub users: Vec<UserName>,
+    /// Recent messages, which were sent before subscribing to `rx`.
+    pub history: Vec<MsgConent>,
+}
+
+pub fn is_valid_room_name(room: &str) -> bool {
+    !room.is_empty() && room.len() <= 16 && room.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+impl Rooms {
+    pub fn new(history: History, transcript: Transcript, events: Arc<Events>) -> Self {
+        let mut rooms = HashMap::new();
+        let lobby = Arc::new(DEFAULT_ROOM.to_string());
+        rooms.insert(lobby.clone(), Room::new(lobby, &events));
+        Self(Arc::new(RwLock::new(RoomsInner {
+            rooms,
+            history,
+            transcript,
+            events,
+        })))
+    }
+
+    /// Announce `name`, connected from `peer`, in `room`, creating the room if needed, and
+    /// subscribe to it.
+    pub async fn join(
+        &self,
+        room_name: &RoomName,
+        name: &UserName,
+        peer: Option<SocketAddr>,
+        history: usize,
+    ) -> Joined {
+        let mut lock = self.0.write().await;
+        let lock = &mut *lock;
+        lock.transcript.write(Kind::Join, room_name, name, "");
+        let history = lock.history.last(room_name, history);
+        let room = lock
+            .rooms
+            .entry(room_name.clone())
+            .or_insert_with(|| Room::new(room_name.clone(), &lock.events));
+
+        // listing, announcing and subscribing under the same lock, so every other user in the room
+        // is either listed, or announced after subscribing
+        let users = room.users.iter().map(Arc::clone).collect();
+
+        // sending only fails if nobody is subscribed, in which case there is nobody to tell
+        let _ = room.tx.send(create_arrival_message(name.clone(), peer));
+        room.users.insert(name.clone());
+
+        Joined {
+            tx: room.tx.clone(),
+            rx: room.tx.subscribe(),
+            users,
+            history,
+        }
+    }
+
+    /// Broadcast a chat message to `room`, and remember it.
+    pub async fn say(&self, room: &RoomName, msg: Msg) -> anyhow::Result<()> {
+        let mut lock = self.0.write().await;
+        if let MsgKind::Chat(text) = &msg.kind {
+            lock.transcript.write(Kind::Msg, room, &msg.from, text);
+        }
+        lock.history.push(room, msg.content.clone());
+        if let Some(entry) = lock.rooms.get(room) {
+            entry.tx.send(msg)?;
+        }
+        Ok(())
+    }
+
+    /// Tell everyone in every room, without remembering it.
+    pub async fn announce(&self, content: &str) {
+        let msg = Msg {
+            from: UserName::default(),
+            peer: None,
+            content: MsgConent::new(content.to_string()),
+            kind: MsgKind::Notice,
+        };
+        for room in self.0.read().await.rooms.values() {
+            let _ = room.tx.send(msg.clone());
+        }
+    }
+
+    pub async fn history(&self, room: &RoomName, n: usize) -> Vec<MsgConent> {
+        self.0.read().await.history.last(room, n)
+    }
+
+    /// Announce that `name`, connected from `peer`, left `room`, removing the room if it is now
+    /// empty.
+    pub async fn leave(&self, room: &RoomName, name: &UserName, peer: Option<SocketAddr>) {
+        let mut lock = self.0.write().await;
+        let lock = &mut *lock;
+        let Some(entry) = lock.rooms.get_mut(room) else {
+            return;
+        };
+
+        lock.transcript.write(Kind::Leave, room, name, "");
+        let _ = entry.tx.send(create_departure_message(name.clone(), peer));
+        entry.users.remove(name);
+
+        if entry.users.is_empty() && room.as_str() != DEFAULT_ROOM {
+            lock.rooms.remove(room);
+        }
+    }
+
+    pub async fn users(&self, room: &RoomName) -> Vec<UserName> {
+        self.0
+            .read()
+            .await
+            .rooms
+            .get(room)
+            .map(|room| room.users.iter().map(Arc::clone).collect())
+            .unwrap_or_default()
+    }
+
+    /// Announce the new name of a user in `room`.
+    pub async fn rename(&self, room: &RoomName, old_name: &UserName, new_name: &UserName) {
+        let mut lock = self.0.write().await;
+        let lock = &mut *lock;
+        let Some(entry) = lock.rooms.get_mut(room) else {
+            return;
+        };
+
+        lock.transcript.write(Kind::Nick, room, old_name, new_name);
+        let _ = entry.tx.send(Msg {
+            content: MsgConent::new(format!("* {old_name} is now known as {new_name}\n")),
+            from: new_name.clone(),
+            peer: None,
+            kind: MsgKind::Rename(old_name.clone()),
+        });
+        entry.users.remove(old_name);
+        entry.users.insert(new_name.clone());
+    }
+
+    /// Names of all rooms with their number of users, default room first.
+    pub async fn list(&self) -> Vec<(RoomName, usize)> {
+        let lock = self.0.read().await;
+
+        let mut rooms: Vec<_> = lock
+            .rooms
+            .iter()
+            .map(|(name, room)| (name.clone(), room.users.len()))
+            .collect();
+        rooms.sort_by(|(a, _), (b, _)| {
+            (a.as_str() != DEFAULT_ROOM, a).cmp(&(b.as_str() != DEFAULT_ROOM, b))
+        });
+
+        rooms
+    }
+}
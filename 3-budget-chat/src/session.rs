@@ -0,0 +1,279 @@
+use std::{mem, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    time::Instant,
+};
+
+use crate::{
+    create_current_users_message,
+    federation::Event,
+    flood::{Flood, Verdict},
+    room::{Joined, RoomName, DEFAULT_ROOM},
+    text, Msg, MsgKind, State, UserName,
+};
+
+/// Everything a joined user can do, independent of how they are connected.
+///
+/// Anything meant only for this user is collected with [`Session::reply`], and has to be sent out
+/// by the caller after every call, see [`Session::take_output`].
+pub struct Session {
+    pub state: State,
+    pub name: UserName,
+    /// Where the user is connected from, `None` for bots.
+    pub peer: Option<SocketAddr>,
+    pub room: RoomName,
+    pub joined: Joined,
+    pub direct_rx: mpsc::Receiver<Msg>,
+    /// Whether this user may moderate others.
+    pub operator: bool,
+    output: String,
+    lags: u32,
+    flood: Flood,
+    active_at: Instant,
+    idle_warned: bool,
+}
+
+impl Session {
+    /// Enter the default room. `name` has to be already reserved in `state.users`.
+    pub async fn start(
+        state: State,
+        name: UserName,
+        peer: Option<SocketAddr>,
+        direct_rx: mpsc::Receiver<Msg>,
+    ) -> Self {
+        let room = RoomName::new(DEFAULT_ROOM.to_string());
+        let joined = state
+            .rooms
+            .join(&room, &name, peer, state.config.history_on_join)
+            .await;
+
+        state.federation.publish(Event::Join {
+            room: room.clone(),
+            user: name.clone(),
+        });
+
+        let mut session = Self {
+            output: create_current_users_message(&joined.users),
+            flood: Flood::new(&state.config.flood),
+            active_at: Instant::now(),
+            idle_warned: false,
+            operator: state.moderation.is_operator(&name),
+            state,
+            name,
+            peer,
+            room,
+            joined,
+            direct_rx,
+            lags: 0,
+        };
+        session.reply_history();
+        session
+    }
+
+    fn reply_history(&mut self) {
+        for content in mem::take(&mut self.joined.history) {
+            self.output.push_str(&content);
+        }
+    }
+
+    pub fn reply(&mut self, msg: impl AsRef<str>) {
+        self.output.push_str(msg.as_ref());
+    }
+
+    pub fn take_output(&mut self) -> String {
+        mem::take(&mut self.output)
+    }
+
+    /// Handle a line sent by the user. Returns `false` if the user has to be disconnected.
+    pub async fn handle_line(&mut self, line: &str) -> anyhow::Result<bool> {
+        self.touch();
+
+        match self.check_flood(line) {
+            Verdict::Accept => {}
+            Verdict::Reject(warning) => {
+                self.reply(warning);
+                return Ok(true);
+            }
+            Verdict::Disconnect(reason) => {
+                self.reply(reason);
+                return Ok(false);
+            }
+        }
+
+        self.dispatch(line).await?;
+        Ok(true)
+    }
+
+    /// Run a command, or say anything else in the current room.
+    pub async fn dispatch(&mut self, line: &str) -> anyhow::Result<()> {
+        match line.strip_prefix('/') {
+            Some(command) => {
+                let commands = self.state.commands.clone();
+                commands.run(self, command).await
+            }
+            None => self.say(line).await,
+        }
+    }
+
+    /// Note that the user is still there, resetting the idle timeout.
+    pub fn touch(&mut self) {
+        self.active_at = Instant::now();
+        self.idle_warned = false;
+    }
+
+    /// When [`Session::idle`] has to be called next, if there is an idle timeout.
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        let timeout = self.state.config.idle_timeout?;
+        let timeout_at = self.active_at + timeout;
+
+        match self.idle_warned {
+            true => Some(timeout_at),
+            false => Some(timeout_at - self.state.config.idle_warning.min(timeout)),
+        }
+    }
+
+    /// Warn the user about being idle, or tell them they are disconnected once the idle timeout
+    /// passed. Returns `false` if the user has to be disconnected.
+    pub fn idle(&mut self) -> bool {
+        if self.idle_warned {
+            self.reply("* disconnecting, you were idle for too long\n");
+            return false;
+        }
+
+        self.idle_warned = true;
+        // the time actually left, which is shorter if the warning came late
+        let left = self.idle_deadline().map_or(Duration::ZERO, |at| {
+            at.saturating_duration_since(Instant::now())
+        });
+        let secs = left.as_secs_f64().round();
+        self.reply(format!(
+            "* you are idle, you will be disconnected in {secs} seconds unless you send something\n"
+        ));
+        true
+    }
+
+    /// Apply the flood protection limits to `line`, before it is handled.
+    pub fn check_flood(&mut self, line: &str) -> Verdict {
+        self.flood.check(&self.state.config.flood, line)
+    }
+
+    /// Handle the next message from the current room. Returns `false` if the user has to be
+    /// disconnected.
+    pub fn receive(&mut self, res: Result<Msg, RecvError>) -> bool {
+        match res {
+            Ok(msg) => {
+                if msg.from != self.name {
+                    self.reply(msg.content.as_str());
+                }
+                true
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                self.lags += 1;
+                self.reply(format!(
+                    "* you fell behind, {skipped} messages were skipped\n"
+                ));
+
+                match self.state.config.max_lags {
+                    Some(max_lags) if self.lags > max_lags => {
+                        self.reply("* disconnecting, you fell behind too many times\n");
+                        false
+                    }
+                    _ => true,
+                }
+            }
+            Err(RecvError::Closed) => false,
+        }
+    }
+
+    /// Handle a message sent only to this user. Returns `false` if the user has to be
+    /// disconnected.
+    pub fn receive_direct(&mut self, msg: Msg) -> bool {
+        self.reply(msg.content.as_str());
+        !matches!(msg.kind, MsgKind::Kick)
+    }
+
+    /// Whether an operator muted this user, telling them so.
+    pub async fn check_muted(&mut self) -> bool {
+        let muted = self.state.moderation.is_muted(&self.name).await;
+        if muted {
+            self.reply("* you were muted by an operator\n");
+        }
+        muted
+    }
+
+    /// Broadcast a chat message to the current room.
+    pub async fn say(&mut self, line: &str) -> anyhow::Result<()> {
+        if self.check_muted().await {
+            return Ok(());
+        }
+
+        let text = Arc::new(text::sanitize(line).into_owned());
+        let msg = Msg {
+            from: self.name.clone(),
+            peer: self.peer,
+            content: Arc::new(format!("[{}] {text}\n", self.name)),
+            kind: MsgKind::Chat(text.clone()),
+        };
+        self.state.rooms.say(&self.room, msg).await?;
+
+        self.state.federation.publish(Event::Say {
+            room: self.room.clone(),
+            user: self.name.clone(),
+            text,
+        });
+        Ok(())
+    }
+
+    pub async fn move_to(&mut self, room: RoomName) {
+        self.state
+            .rooms
+            .leave(&self.room, &self.name, self.peer)
+            .await;
+        self.state.federation.publish(Event::Leave {
+            room: self.room.clone(),
+            user: self.name.clone(),
+        });
+
+        self.room = room;
+        self.state.federation.publish(Event::Join {
+            room: self.room.clone(),
+            user: self.name.clone(),
+        });
+        self.joined = self
+            .state
+            .rooms
+            .join(
+                &self.room,
+                &self.name,
+                self.peer,
+                self.state.config.history_on_join,
+            )
+            .await;
+
+        self.reply(format!("* you are now in {}\n", self.room));
+        self.reply(create_current_users_message(&self.joined.users));
+        self.reply_history();
+    }
+
+    /// Make absence noticed, and release the name.
+    pub async fn finish(self) {
+        self.state
+            .rooms
+            .leave(&self.room, &self.name, self.peer)
+            .await;
+        self.state.federation.publish(Event::Leave {
+            room: self.room.clone(),
+            user: self.name.clone(),
+        });
+        self.state.users.write().await.remove(&self.name);
+    }
+}
+
+/// Sleep until `deadline`, or forever without one.
+pub async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,273 @@
+//! Checking what users send: the names they choose, and the text relayed to others.
+
+use std::{borrow::Cow, iter::Peekable, str::FromStr};
+
+use anyhow::anyhow;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Longest name, in characters, or in graphemes for [`NamePolicy::Unicode`].
+pub const MAX_NAME_LEN: usize = 16;
+
+/// Why a user can't have the name they asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Empty,
+    /// Has characters other than letters and digits.
+    InvalidChars,
+    /// Longer than [`MAX_NAME_LEN`].
+    TooLong,
+    /// Used by someone else, or too similar to a name used by someone else.
+    Duplicate,
+    Banned,
+}
+
+impl Rejection {
+    /// Name of the rule, for tools rather than users.
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::InvalidChars => "invalid_chars",
+            Self::TooLong => "too_long",
+            Self::Duplicate => "duplicate",
+            Self::Banned => "banned",
+        }
+    }
+}
+
+/// Which names are accepted, configured with `--names=<policy>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NamePolicy {
+    /// `ascii`: ASCII letters and digits only, as the protocol asks for.
+    #[default]
+    Ascii,
+    /// `unicode`: letters and digits of any script, combining marks included. Names are
+    /// normalized to NFC, and refused when they look like a name already in use.
+    Unicode,
+}
+
+impl FromStr for NamePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ascii" => Ok(NamePolicy::Ascii),
+            "unicode" => Ok(NamePolicy::Unicode),
+            s => Err(anyhow!(
+                "unknown name policy {s:?}, expected ascii or unicode"
+            )),
+        }
+    }
+}
+
+impl NamePolicy {
+    /// `name` as it is stored and shown, or why it isn't a valid name.
+    pub fn validate(self, name: &str) -> Result<String, Rejection> {
+        match self {
+            Self::Ascii => {
+                if name.is_empty() {
+                    Err(Rejection::Empty)
+                } else if !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    Err(Rejection::InvalidChars)
+                } else if name.len() > MAX_NAME_LEN {
+                    Err(Rejection::TooLong)
+                } else {
+                    Ok(name.to_string())
+                }
+            }
+            Self::Unicode => {
+                let name = name.nfc().collect::<String>();
+                let graphemes = name.graphemes(true).collect::<Vec<_>>();
+                if graphemes.is_empty() {
+                    Err(Rejection::Empty)
+                } else if !graphemes.iter().all(|grapheme| is_name_grapheme(grapheme)) {
+                    Err(Rejection::InvalidChars)
+                } else if graphemes.len() > MAX_NAME_LEN {
+                    Err(Rejection::TooLong)
+                } else {
+                    Ok(name)
+                }
+            }
+        }
+    }
+
+    /// Whether `name` can't be told apart from one of `names`.
+    pub fn is_taken<'a>(self, name: &str, names: impl IntoIterator<Item = &'a str>) -> bool {
+        match self {
+            Self::Ascii => names.into_iter().any(|other| other == name),
+            Self::Unicode => {
+                let skeleton = unicode_security::skeleton(name).collect::<String>();
+                names.into_iter().any(|other| {
+                    other == name || unicode_security::skeleton(other).eq(skeleton.chars())
+                })
+            }
+        }
+    }
+}
+
+/// A letter or digit, possibly followed by combining marks.
+fn is_name_grapheme(grapheme: &str) -> bool {
+    let mut chars = grapheme.chars();
+    chars.next().is_some_and(char::is_alphanumeric)
+        && chars.all(|c| c.is_alphanumeric() || is_combining_mark(c))
+}
+
+/// `text` without control characters and terminal escape sequences, which could mess with the
+/// terminal of whoever reads it. Tabs become spaces.
+pub fn sanitize(text: &str) -> Cow<'_, str> {
+    if !text.chars().any(|c| c.is_control() || is_bidi_control(c)) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut res = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\t' => res.push(' '),
+            '\x1b' => skip_escape(&mut chars),
+            // 8-bit CSI and string introducers
+            '\u{9b}' => skip_csi(&mut chars),
+            '\u{90}' | '\u{98}' | '\u{9d}' | '\u{9e}' | '\u{9f}' => skip_string(&mut chars),
+            c if c.is_control() || is_bidi_control(c) => {}
+            c => res.push(c),
+        }
+    }
+    Cow::Owned(res)
+}
+
+/// Reorders the text around it, and can make a message look like it says something else.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Skip what follows an ESC.
+fn skip_escape(chars: &mut Peekable<impl Iterator<Item = char>>) {
+    match chars.peek() {
+        Some('[') => {
+            chars.next();
+            skip_csi(chars);
+        }
+        // OSC, DCS, SOS, PM and APC run until a terminator
+        Some(']' | 'P' | 'X' | '^' | '_') => {
+            chars.next();
+            skip_string(chars);
+        }
+        // any other sequence is ESC, optional intermediate bytes, and a final byte
+        _ => {
+            while chars.next_if(|c| matches!(c, ' '..='/')).is_some() {}
+            chars.next_if(|c| matches!(c, '0'..='~'));
+        }
+    }
+}
+
+/// Skip the parameters and final byte of a control sequence.
+fn skip_csi(chars: &mut Peekable<impl Iterator<Item = char>>) {
+    while chars.next_if(|c| matches!(c, ' '..='?')).is_some() {}
+    chars.next_if(|c| matches!(c, '@'..='~'));
+}
+
+/// Skip a control string, up to and including BEL or ST.
+fn skip_string(chars: &mut Peekable<impl Iterator<Item = char>>) {
+    while let Some(c) = chars.next() {
+        match c {
+            '\x07' | '\u{9c}' => return,
+            '\x1b' => {
+                chars.next_if_eq(&'\\');
+                return;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_names() {
+        let policy = NamePolicy::Ascii;
+        assert_eq!(policy.validate("alice42"), Ok("alice42".to_string()));
+        assert_eq!(policy.validate(""), Err(Rejection::Empty));
+        assert_eq!(policy.validate("al ice"), Err(Rejection::InvalidChars));
+        assert_eq!(policy.validate("zoë"), Err(Rejection::InvalidChars));
+        assert!(policy.validate(&"a".repeat(MAX_NAME_LEN)).is_ok());
+        assert_eq!(
+            policy.validate(&"a".repeat(MAX_NAME_LEN + 1)),
+            Err(Rejection::TooLong)
+        );
+    }
+
+    #[test]
+    fn unicode_names_are_nfc() {
+        let policy = NamePolicy::Unicode;
+        // e followed by a combining acute accent
+        assert_eq!(policy.validate("rene\u{301}"), Ok("ren\u{e9}".to_string()));
+        assert_eq!(policy.validate("ren\u{e9}"), Ok("ren\u{e9}".to_string()));
+        assert_eq!(policy.validate("Ωμέγα"), Ok("Ωμέγα".to_string()));
+        assert_eq!(policy.validate("\u{301}abc"), Err(Rejection::InvalidChars));
+        assert_eq!(policy.validate("a-b"), Err(Rejection::InvalidChars));
+    }
+
+    #[test]
+    fn unicode_length_counts_graphemes() {
+        let policy = NamePolicy::Unicode;
+        // 32 code points, but 16 graphemes
+        let name = "e\u{301}".repeat(MAX_NAME_LEN);
+        assert!(policy.validate(&name).is_ok());
+        assert_eq!(
+            policy.validate(&"é".repeat(MAX_NAME_LEN + 1)),
+            Err(Rejection::TooLong)
+        );
+    }
+
+    #[test]
+    fn ascii_taken_is_exact() {
+        let policy = NamePolicy::Ascii;
+        assert!(policy.is_taken("alice", ["bob", "alice"]));
+        assert!(!policy.is_taken("Alice", ["alice"]));
+        assert!(!policy.is_taken("alice", []));
+    }
+
+    #[test]
+    fn unicode_taken_by_skeleton() {
+        let policy = NamePolicy::Unicode;
+        // Cyrillic а
+        assert!(policy.is_taken("\u{430}lice", ["alice"]));
+        assert!(policy.is_taken("paypa1", ["paypal"]));
+        assert!(policy.is_taken("alice", ["alice"]));
+        assert!(!policy.is_taken("alice", ["bob"]));
+    }
+
+    #[test]
+    fn policy_from_str() {
+        assert_eq!("ascii".parse::<NamePolicy>().unwrap(), NamePolicy::Ascii);
+        assert_eq!(
+            "unicode".parse::<NamePolicy>().unwrap(),
+            NamePolicy::Unicode
+        );
+        assert!("utf8".parse::<NamePolicy>().is_err());
+    }
+
+    #[test]
+    fn sanitize_keeps_plain_text() {
+        assert!(matches!(sanitize("hello, wörld"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn sanitize_strips_escapes() {
+        assert_eq!(sanitize("\x1b[31mred\x1b[0m"), "red");
+        assert_eq!(sanitize("\x1b]0;title\x07text"), "text");
+        assert_eq!(sanitize("\x1b]8;;http://x\x1b\\link"), "link");
+        assert_eq!(sanitize("a\u{9b}2Jb"), "ab");
+        assert_eq!(sanitize("\x1bcreset"), "reset");
+    }
+
+    #[test]
+    fn sanitize_strips_controls() {
+        assert_eq!(sanitize("a\tb"), "a b");
+        assert_eq!(sanitize("a\rb\x07c\x00"), "abc");
+        assert_eq!(sanitize("evil\u{202e}txt.exe"), "eviltxt.exe");
+        assert_eq!(sanitize("\u{2066}x\u{2069}"), "x");
+    }
+}
@@ -0,0 +1,267 @@
+//! Transcript of everything happening in every room, kept in `--transcript-dir=<dir>`.
+//!
+//! Every line is `<time>\t<kind>\t<room>\t<user>\t<text>`, with the time in RFC 3339 and UTC, and
+//! kind one of `join`, `leave`, `msg` or `nick` (with the new name as text). The current file is
+//! `transcript.log`, once it grows past `--transcript-max-bytes` (1 MiB by default) it is renamed
+//! to `transcript-<unix time in nanoseconds>.log` and a new one is started.
+//!
+//! The archive can be searched from the chat with `/search <term>`, or offline with
+//! `budget-chat --archive=<dir> [--user=<name>] [--room=<room>] [--keyword=<term>]
+//! [--since=<time>] [--until=<time>] [--limit=<n>]`.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::error;
+
+const CURRENT: &str = "transcript.log";
+
+/// Rotate once the current file is bigger than this, unless configured otherwise.
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Join,
+    Leave,
+    Msg,
+    Nick,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Join => "join",
+            Self::Leave => "leave",
+            Self::Msg => "msg",
+            Self::Nick => "nick",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "join" => Some(Self::Join),
+            "leave" => Some(Self::Leave),
+            "msg" => Some(Self::Msg),
+            "nick" => Some(Self::Nick),
+            _ => None,
+        }
+    }
+}
+
+struct Writer {
+    dir: PathBuf,
+    max_bytes: u64,
+    file: File,
+    written: u64,
+}
+
+/// Writes the transcript, or does nothing if it is disabled.
+pub struct Transcript(Option<Writer>);
+
+impl Transcript {
+    pub fn disabled() -> Self {
+        Self(None)
+    }
+
+    pub fn open(dir: &Path, max_bytes: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let file = open_current(dir)?;
+
+        Ok(Self(Some(Writer {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            written: file.metadata()?.len(),
+            file,
+        })))
+    }
+
+    pub fn write(&mut self, kind: Kind, room: &str, user: &str, text: &str) {
+        let Some(writer) = &mut self.0 else {
+            return;
+        };
+
+        if let Err(e) = writer.write(kind, room, user, text) {
+            error!("failed to write transcript: {e}");
+        }
+    }
+}
+
+impl Writer {
+    fn write(&mut self, kind: Kind, room: &str, user: &str, text: &str) -> anyhow::Result<()> {
+        if self.written >= self.max_bytes {
+            self.rotate()?;
+        }
+
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+        let line = format!("{now}\t{}\t{room}\t{user}\t{text}\n", kind.as_str());
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        let now = OffsetDateTime::now_utc().unix_timestamp_nanos();
+        let rotated = self.dir.join(format!("transcript-{now}.log"));
+        fs::rename(self.dir.join(CURRENT), rotated)?;
+
+        self.file = open_current(&self.dir)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn open_current(dir: &Path) -> anyhow::Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(CURRENT))?)
+}
+
+pub struct Entry {
+    pub at: OffsetDateTime,
+    pub kind: Kind,
+    pub room: String,
+    pub user: String,
+    pub text: String,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = self
+            .at
+            .replace_nanosecond(0)
+            .ok()
+            .and_then(|at| at.format(&Rfc3339).ok())
+            .unwrap_or_default();
+        let Self {
+            room, user, text, ..
+        } = self;
+
+        match self.kind {
+            Kind::Join => write!(f, "{at} #{room} * {user} has entered the room"),
+            Kind::Leave => write!(f, "{at} #{room} * {user} has left the room"),
+            Kind::Msg => write!(f, "{at} #{room} [{user}] {text}"),
+            Kind::Nick => write!(f, "{at} #{room} * {user} is now known as {text}"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Query {
+    pub user: Option<String>,
+    pub room: Option<String>,
+    /// Matched case insensitively against messages.
+    pub keyword: Option<String>,
+    pub since: Option<OffsetDateTime>,
+    pub until: Option<OffsetDateTime>,
+    /// Only keep the most recent matches.
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    pub fn from_args(args: &util::Args) -> anyhow::Result<Self> {
+        Ok(Self {
+            user: args.value("user")?,
+            room: args.value("room")?,
+            keyword: args.value("keyword")?,
+            since: parse_time(args.value("since")?)?,
+            until: parse_time(args.value("until")?)?,
+            limit: args.value("limit")?,
+        })
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        self.user.as_ref().is_none_or(|user| *user == entry.user)
+            && self.room.as_ref().is_none_or(|room| *room == entry.room)
+            && self.since.is_none_or(|since| entry.at >= since)
+            && self.until.is_none_or(|until| entry.at < until)
+            && self.keyword.as_ref().is_none_or(|keyword| {
+                entry.kind == Kind::Msg
+                    && entry.text.to_lowercase().contains(&keyword.to_lowercase())
+            })
+    }
+}
+
+fn parse_time(time: Option<String>) -> anyhow::Result<Option<OffsetDateTime>> {
+    time.map(|time| {
+        OffsetDateTime::parse(&time, &Rfc3339)
+            .map_err(|e| anyhow!("invalid time {time:?}, expected RFC 3339: {e}"))
+    })
+    .transpose()
+}
+
+/// Every entry of the archive in `dir` matching `query`, oldest first.
+pub fn search(dir: &Path, query: &Query) -> anyhow::Result<Vec<Entry>> {
+    // rotated files are named after the time they were rotated at, and sort before the current one
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        if name.starts_with("transcript") && name.ends_with(".log") {
+            paths.push(path);
+        }
+    }
+    paths.sort_by_key(|path| rotated_at(path));
+
+    let mut res = Vec::new();
+    for path in paths {
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let Some(entry) = parse_line(&line?) else {
+                continue;
+            };
+            if query.matches(&entry) {
+                res.push(entry);
+            }
+        }
+    }
+
+    if let Some(limit) = query.limit {
+        res.drain(..res.len().saturating_sub(limit));
+    }
+
+    Ok(res)
+}
+
+/// Sort key of the files of the archive, with the current one last.
+fn rotated_at(path: &Path) -> (bool, i128) {
+    let rotated_at = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.strip_prefix("transcript-"))
+        .and_then(|at| at.parse().ok());
+
+    match rotated_at {
+        Some(at) => (false, at),
+        None => (true, 0),
+    }
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+    let mut parts = line.splitn(5, '\t');
+
+    Some(Entry {
+        at: OffsetDateTime::parse(parts.next()?, &Rfc3339).ok()?,
+        kind: Kind::parse(parts.next()?)?,
+        room: parts.next()?.to_string(),
+        user: parts.next()?.to_string(),
+        text: parts.next()?.to_string(),
+    })
+}
+
+/// Run a query given on the command line, printing the matches to stdout.
+pub fn run(dir: &Path, args: &util::Args) -> anyhow::Result<()> {
+    let query = Query::from_args(args)?;
+    for entry in search(dir, &query)? {
+        println!("{entry}");
+    }
+    Ok(())
+}
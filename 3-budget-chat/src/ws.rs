@@ -0,0 +1,156 @@
+//! WebSocket gateway, for joining the chat from a browser.
+//!
+//! Every text frame is handled like a line sent over TCP, and every line sent back is its own text
+//! frame, without the trailing newline. Apart from that, WebSocket users go through the same
+//! admission and [`Session`] as TCP users, so they share rooms, names and private messages.
+
+use std::net::SocketAddr;
+
+use anyhow::anyhow;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    tungstenite::{error::ProtocolError, protocol::WebSocketConfig, Error, Message},
+    WebSocketStream,
+};
+use tracing::info;
+
+use crate::{
+    admit, lines,
+    session::{self, Session},
+    with_timeout, State,
+};
+
+type Ws = WebSocketStream<TcpStream>;
+
+pub async fn handle_stream(
+    stream: TcpStream,
+    addr: SocketAddr,
+    state: State,
+) -> anyhow::Result<()> {
+    // a frame is a line, so --max-len bounds it the same way
+    let max_bytes = state.config.flood.max_len.map(lines::max_bytes);
+    let default = WebSocketConfig::default();
+    let config = WebSocketConfig {
+        max_message_size: max_bytes.or(default.max_message_size),
+        max_frame_size: max_bytes.or(default.max_frame_size),
+        ..default
+    };
+    let mut ws = tokio_tungstenite::accept_async_with_config(stream, Some(config)).await?;
+
+    // greet the user
+
+    let lang = state.config.lang;
+    send(&mut ws, lang.welcome()).await?;
+
+    // get the username, validate and reserve it, make presence noticed in the default room
+
+    let mut retries = state.config.name_retries;
+    let mut session = loop {
+        let name = match with_timeout(state.config.name_timeout, next_text(&mut ws)).await {
+            Some(name) => name?.ok_or_else(|| anyhow!("no userame given\n"))?,
+            None => return reject(ws, lang.name_timeout(), addr).await,
+        };
+
+        match admit(state.clone(), name.trim().to_string(), Some(addr)).await {
+            Ok(session) => break session,
+            Err(rejection) if retries == 0 => {
+                return reject(ws, &lang.rejection(rejection), addr).await
+            }
+            Err(rejection) => {
+                retries -= 1;
+                send(&mut ws, &(lang.rejection(rejection) + lang.retry())).await?;
+            }
+        }
+    };
+
+    // send back list of all current users, then chat messages
+
+    let res = chat(&mut ws, &mut session).await;
+
+    // make absence noticed, remove from UsersList, however the chat ended
+
+    session.finish().await;
+
+    res?;
+    let _ = ws.close(None).await;
+    info!("closing websocket with {addr}");
+    Ok(())
+}
+
+async fn reject(mut ws: Ws, msg: &str, addr: SocketAddr) -> anyhow::Result<()> {
+    send(&mut ws, msg).await?;
+    let _ = ws.close(None).await;
+    info!("closing websocket with {addr}");
+    Ok(())
+}
+
+async fn chat(ws: &mut Ws, session: &mut Session) -> anyhow::Result<()> {
+    // what joining produced, written here so a failed send still ends with session.finish()
+    send(ws, &session.take_output()).await?;
+
+    loop {
+        let idle = session.idle_deadline();
+
+        let connected = tokio::select! {
+            res = next_text(ws) => {
+                let Some(text) = res? else {
+                    return Ok(());
+                };
+                let mut connected = true;
+                for line in text.lines() {
+                    connected = session.handle_line(line.trim()).await?;
+                    if !connected {
+                        break;
+                    }
+                }
+                connected
+            }
+            res_msg = session.joined.rx.recv() => session.receive(res_msg),
+            Some(msg) = session.direct_rx.recv() => session.receive_direct(msg),
+            _ = session::sleep_until(idle) => session.idle(),
+        };
+
+        send(ws, &session.take_output()).await?;
+
+        if !connected {
+            return Ok(());
+        }
+    }
+}
+
+/// The next text frame, skipping control frames. `None` once the client closed the connection.
+///
+/// Cancel safe, as `StreamExt::next` is.
+async fn next_text(ws: &mut Ws) -> anyhow::Result<Option<String>> {
+    while let Some(msg) = ws.next().await {
+        let msg = match msg {
+            Ok(msg) => msg,
+            // a closed tab is as good as a close frame
+            Err(Error::ConnectionClosed)
+            | Err(Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        match msg {
+            Message::Text(text) => return Ok(Some(text)),
+            Message::Binary(bytes) => return Ok(Some(String::from_utf8(bytes)?)),
+            Message::Close(_) => return Ok(None),
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Send every line of `output` as its own text frame.
+async fn send(ws: &mut Ws, output: &str) -> anyhow::Result<()> {
+    if output.is_empty() {
+        return Ok(());
+    }
+
+    for line in output.lines() {
+        ws.feed(Message::Text(line.to_string())).await?;
+    }
+    ws.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,44 @@
+//! Run with `cargo +nightly fuzz run packet` from `4-unusual-db`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use unusual_db::packet::{self, Invalid, Request, MAX_PACKET_LEN, TTL_PREFIX};
+
+fuzz_target!(|data: &[u8]| {
+    for ttl in [false, true] {
+        let request = match packet::parse(data, ttl) {
+            Ok(request) => request,
+            Err(Invalid::TooLong) => {
+                assert!(data.len() > MAX_PACKET_LEN);
+                continue;
+            }
+            Err(Invalid::Ttl) => {
+                assert!(ttl && data.starts_with(TTL_PREFIX));
+                continue;
+            }
+        };
+        assert!(data.len() <= MAX_PACKET_LEN);
+
+        match request {
+            Request::Query { key } => {
+                assert_eq!(key, data);
+                assert!(!key.contains(&b'='));
+            }
+            Request::Insert { key, value } => {
+                // what a query of the key is answered with is exactly the insert
+                assert_eq!(packet::response(key, value).as_deref(), Some(data));
+                assert!(!key.contains(&b'='));
+            }
+            Request::TtlQuery { key } => {
+                assert!(ttl);
+                assert_eq!([TTL_PREFIX, key].concat(), data);
+            }
+            Request::TtlInsert { key, value, .. } => {
+                assert!(ttl);
+                assert!(packet::response(key, value).is_some());
+                assert!(!key.contains(&b'='));
+            }
+        }
+    }
+});
@@ -0,0 +1,3 @@
+//! The parts of the server which are fuzzed, see `fuzz/`.
+
+pub mod packet;
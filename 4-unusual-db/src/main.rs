@@ -1,58 +1,169 @@
-use std::{collections::HashMap, env, net::SocketAddr};
+mod replication;
+mod store;
+
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
 
 use tokio::net::UdpSocket;
-use tracing::info;
+use tracing::{error, info, warn};
+
+use replication::Replication;
+use store::Store;
+use unusual_db::packet::{self, Invalid, Request, MAX_PACKET_LEN};
+
+const VERSION: &[u8] = b"Abhik's attempt at Protohack Q4: v1.1";
 
-const EMPTY: &'static Vec<u8> = &Vec::new();
+/// How often expired keys are removed, they aren't returned by queries in the meantime either.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt().init();
 
-    let addr: SocketAddr = env::args().nth(1).expect("no addr").parse()?;
+    let args = util::Args::from_env();
+    let addr = args.addr()?;
+    let mut db = store::from_spec(&args.value_or("store", "memory".to_string())?)?;
+    let ttl = args.flag("ttl");
+    let mut replication = Replication::from_args(&args).await?;
 
     let socket = UdpSocket::bind(addr).await?;
-    let buf = &mut vec![0; 1000];
-
-    let mut db = HashMap::new();
+    // one byte more than a request can have, to tell when a datagram is too long
+    let buf = &mut vec![0; MAX_PACKET_LEN + 1];
+    let replication_buf = &mut vec![0; replication::MAX_MESSAGE_LEN];
+    let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
 
     loop {
-        let (bytes_read, addr) = socket.recv_from(buf).await?;
-        let mut data = buf[..bytes_read].splitn(2, |b| *b == b'=');
+        let (bytes_read, addr) = tokio::select! {
+            res = socket.recv_from(buf) => res?,
+            res = replication::recv(replication.as_ref(), replication_buf) => {
+                let (len, from) = res?;
+                if let Some(replication) = &mut replication {
+                    replication.handle(db.as_mut(), &replication_buf[..len], from).await;
+                }
+                continue;
+            }
+            _ = sweep.tick() => {
+                db.sweep();
+                if let Some(replication) = &mut replication {
+                    replication.tick().await;
+                }
+                continue;
+            }
+        };
+        let packet = &buf[..bytes_read];
 
-        let key = data.next().expect("there should atleast be an empty slice");
+        let request = match packet::parse(packet, ttl) {
+            Ok(request) => request,
+            Err(Invalid::TooLong) => {
+                warn!("addr = {addr}, ignoring request of more than {MAX_PACKET_LEN} bytes");
+                continue;
+            }
+            Err(Invalid::Ttl) => {
+                info!("addr = {addr}, ignoring insert with invalid ttl");
+                continue;
+            }
+        };
 
-        info!("addr = {addr}, key = {key:?}");
+        info!("addr = {addr}, request = {request:?}");
 
-        if key == b"version" {
-            if data.next().is_none() {
-                socket
-                    .send_to(b"version=Abhik's attempt at Protohack Q4: v1.1", addr)
-                    .await?;
+        match request {
+            Request::Query { key: b"version" } => {
+                respond(&socket, packet, VERSION, addr).await?;
+            }
+            Request::Insert {
+                key: b"version", ..
+            } => info!("ignoring insert of version"),
+            Request::Query { key } => {
+                let value = db
+                    .get(key)
+                    .map(|entry| entry.value.as_slice())
+                    .unwrap_or_default();
+                respond(&socket, packet, value, addr).await?;
             }
-            continue;
+            Request::TtlQuery { key } => {
+                let remaining = remaining_ttl(db.as_ref(), key);
+                respond(&socket, packet, remaining.as_bytes(), addr).await?;
+            }
+            Request::Insert { key, value } => {
+                client_insert(db.as_mut(), replication.as_mut(), key, value, None).await;
+            }
+            Request::TtlInsert { key, value, ttl } => match SystemTime::now().checked_add(ttl) {
+                Some(expires_at) => {
+                    let replication = replication.as_mut();
+                    client_insert(db.as_mut(), replication, key, value, Some(expires_at)).await;
+                }
+                None => info!("ignoring insert with invalid ttl"),
+            },
         }
+    }
 
-        match data.next() {
-            None => {
-                info!("query");
-
-                let value = db.get(key).unwrap_or(EMPTY);
+    #[allow(unreachable_code)]
+    Ok(())
+}
 
-                buf[bytes_read] = b'=';
-                let start = bytes_read + 1;
-                let end = start + value.len();
-                buf[start..end].copy_from_slice(value);
+/// Send `<key>=<value>` to `addr`, unless it is too long.
+async fn respond(socket: &UdpSocket, key: &[u8], value: &[u8], addr: SocketAddr) -> io::Result<()> {
+    match packet::response(key, value) {
+        Some(response) => {
+            socket.send_to(&response, addr).await?;
+        }
+        None => warn!("not answering {addr}, the response would be over {MAX_PACKET_LEN} bytes"),
+    }
+    Ok(())
+}
 
-                socket.send_to(&buf[..end], addr).await?;
-            }
-            Some(value) => {
-                info!("insert");
-                db.insert(Vec::from(key), Vec::from(value));
+/// Insert what a client sent, and replicate it, unless this is a replica.
+async fn client_insert(
+    db: &mut dyn Store,
+    replication: Option<&mut Replication>,
+    key: &[u8],
+    value: &[u8],
+    expires_at: Option<SystemTime>,
+) {
+    match replication {
+        Some(replication) if !replication.is_primary() => {
+            info!("ignoring insert, this is a replica")
+        }
+        replication => {
+            if insert(db, key, value, expires_at) {
+                if let Some(replication) = replication {
+                    replication.replicate(key, value, expires_at).await;
+                }
             }
         }
     }
+}
 
-    #[allow(unreachable_code)]
-    Ok(())
+/// Returns whether the key was stored. Keys are refused if querying them couldn't be answered.
+fn insert(db: &mut dyn Store, key: &[u8], value: &[u8], expires_at: Option<SystemTime>) -> bool {
+    if packet::response(key, value).is_none() {
+        warn!("refusing to store {key:?}, its value is too long to be sent");
+        return false;
+    }
+
+    match db.insert(key, value, expires_at) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("failed to store {key:?}: {e}");
+            false
+        }
+    }
+}
+
+/// Whole seconds `key` has left, rounded up.
+fn remaining_ttl(db: &dyn Store, key: &[u8]) -> String {
+    match db.get(key).map(|entry| entry.expires_at) {
+        None => String::new(),
+        Some(None) => "-1".to_string(),
+        Some(Some(expires_at)) => {
+            let left = expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            let secs = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+            secs.to_string()
+        }
+    }
 }
@@ -0,0 +1,223 @@
+//! Reading requests and writing responses, which the protocol keeps shorter than 1000 bytes.
+//!
+//! Requests any longer are dropped rather than cut short. Inserts are refused when querying the
+//! key couldn't be answered, so the only queries left without a response are those of keys too long
+//! to have been inserted, and TTL queries of keys too long for the seconds to fit.
+
+use std::time::Duration;
+
+/// Longest request or response.
+pub const MAX_PACKET_LEN: usize = 999;
+
+/// With `--ttl`, keys starting with this are reserved: inserting `ttl:<seconds>:<key>=<value>`
+/// inserts `key` so it expires after `seconds`, and querying `ttl:<key>` is answered with the
+/// seconds `key` has left, `-1` if it doesn't expire, or nothing if it doesn't exist. Plain inserts
+/// keep the key until it is overwritten, also if it was inserted with a TTL before.
+pub const TTL_PREFIX: &[u8] = b"ttl:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    Query {
+        key: &'a [u8],
+    },
+    Insert {
+        key: &'a [u8],
+        value: &'a [u8],
+    },
+    /// `ttl:<key>`, with `--ttl`.
+    TtlQuery {
+        key: &'a [u8],
+    },
+    /// `ttl:<seconds>:<key>=<value>`, with `--ttl`.
+    TtlInsert {
+        key: &'a [u8],
+        value: &'a [u8],
+        ttl: Duration,
+    },
+}
+
+/// Why a request is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invalid {
+    /// Longer than [`MAX_PACKET_LEN`].
+    TooLong,
+    /// A TTL insert without a valid number of seconds.
+    Ttl,
+}
+
+/// Read the request in `packet`, with TTLs if `ttl`.
+pub fn parse(packet: &[u8], ttl: bool) -> Result<Request<'_>, Invalid> {
+    if packet.len() > MAX_PACKET_LEN {
+        return Err(Invalid::TooLong);
+    }
+
+    let (key, value) = match packet.iter().position(|b| *b == b'=') {
+        Some(sep) => (&packet[..sep], Some(&packet[sep + 1..])),
+        None => (packet, None),
+    };
+
+    let Some(ttl_key) = key.strip_prefix(TTL_PREFIX).filter(|_| ttl) else {
+        return Ok(match value {
+            None => Request::Query { key },
+            Some(value) => Request::Insert { key, value },
+        });
+    };
+    match value {
+        None => Ok(Request::TtlQuery { key: ttl_key }),
+        Some(value) => {
+            let sep = ttl_key
+                .iter()
+                .position(|b| *b == b':')
+                .ok_or(Invalid::Ttl)?;
+            let secs = std::str::from_utf8(&ttl_key[..sep])
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .ok_or(Invalid::Ttl)?;
+            Ok(Request::TtlInsert {
+                key: &ttl_key[sep + 1..],
+                value,
+                ttl: Duration::from_secs(secs),
+            })
+        }
+    }
+}
+
+/// `<key>=<value>`, or `None` if it is too long to send.
+pub fn response(key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+    if key.len() + 1 + value.len() > MAX_PACKET_LEN {
+        return None;
+    }
+
+    let mut response = Vec::with_capacity(key.len() + 1 + value.len());
+    response.extend_from_slice(key);
+    response.push(b'=');
+    response.extend_from_slice(value);
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_boundary() {
+        let longest = vec![b'k'; MAX_PACKET_LEN];
+        assert_eq!(
+            parse(&longest, false),
+            Ok(Request::Query { key: &longest[..] })
+        );
+
+        let too_long = vec![b'k'; MAX_PACKET_LEN + 1];
+        assert_eq!(parse(&too_long, false), Err(Invalid::TooLong));
+        assert_eq!(parse(&too_long, true), Err(Invalid::TooLong));
+    }
+
+    #[test]
+    fn splits_at_first_equals() {
+        assert_eq!(parse(b"foo", false), Ok(Request::Query { key: b"foo" }));
+        assert_eq!(
+            parse(b"foo=bar=baz", false),
+            Ok(Request::Insert {
+                key: b"foo",
+                value: b"bar=baz"
+            })
+        );
+        assert_eq!(
+            parse(b"=foo", false),
+            Ok(Request::Insert {
+                key: b"",
+                value: b"foo"
+            })
+        );
+        assert_eq!(
+            parse(b"foo=", false),
+            Ok(Request::Insert {
+                key: b"foo",
+                value: b""
+            })
+        );
+        assert_eq!(
+            parse(b"===", false),
+            Ok(Request::Insert {
+                key: b"",
+                value: b"=="
+            })
+        );
+        assert_eq!(parse(b"", false), Ok(Request::Query { key: b"" }));
+    }
+
+    #[test]
+    fn ttl_prefix_is_plain_without_ttl() {
+        assert_eq!(
+            parse(b"ttl:5:key=value", false),
+            Ok(Request::Insert {
+                key: b"ttl:5:key",
+                value: b"value"
+            })
+        );
+        assert_eq!(
+            parse(b"ttl:key", false),
+            Ok(Request::Query { key: b"ttl:key" })
+        );
+    }
+
+    #[test]
+    fn ttl_requests() {
+        assert_eq!(
+            parse(b"ttl:key", true),
+            Ok(Request::TtlQuery { key: b"key" })
+        );
+        assert_eq!(
+            parse(b"ttl:30:key=a=b", true),
+            Ok(Request::TtlInsert {
+                key: b"key",
+                value: b"a=b",
+                ttl: Duration::from_secs(30),
+            })
+        );
+        // the key can have colons of its own
+        assert_eq!(
+            parse(b"ttl:0:a:b=", true),
+            Ok(Request::TtlInsert {
+                key: b"a:b",
+                value: b"",
+                ttl: Duration::ZERO,
+            })
+        );
+        assert_eq!(
+            parse(b"plain=value", true),
+            Ok(Request::Insert {
+                key: b"plain",
+                value: b"value"
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_ttl() {
+        for packet in [
+            &b"ttl:key=value"[..],
+            b"ttl::key=value",
+            b"ttl:-1:key=value",
+            b"ttl:1.5:key=value",
+            b"ttl:99999999999999999999:key=value",
+            b"ttl:\xff:key=value",
+        ] {
+            assert_eq!(parse(packet, true), Err(Invalid::Ttl), "{packet:?}");
+        }
+    }
+
+    #[test]
+    fn response_length() {
+        assert_eq!(response(b"foo", b"bar"), Some(b"foo=bar".to_vec()));
+
+        // key, `=` and value make up the whole packet
+        let key = vec![b'k'; 500];
+        let value = vec![b'v'; MAX_PACKET_LEN - 501];
+        assert_eq!(response(&key, &value).unwrap().len(), MAX_PACKET_LEN);
+
+        let value = vec![b'v'; MAX_PACKET_LEN - 500];
+        assert_eq!(response(&key, &value), None);
+        assert_eq!(response(&[b'k'; MAX_PACKET_LEN], b""), None);
+    }
+}
@@ -0,0 +1,584 @@
+//! Replication of inserts to hot standbys, over UDP.
+//!
+//! Enabled with `--replication=<addr>`, the address the messages below are exchanged on. A
+//! primary sends every insert to the replication addresses in `--replicas=<addr>,...`. With
+//! `--primary=<addr>`, the instance is a replica of the primary with that replication address: it
+//! applies what the primary sends, answers queries, and ignores inserts from clients. Sending
+//! `PROMOTE` to the replication address of a replica makes it a primary, which replicates to its
+//! own `--replicas`. Only `PROMOTE`s sent from the loopback address or an address of
+//! `--replication-admins=<ip>,...` are followed, and only the `--replicas` of a primary can ask it
+//! to resend inserts.
+//!
+//! Stopping the old primary is up to whoever promotes. Other replicas keep following the old
+//! primary, so promoting only keeps everything replicated with a single replica, any others have
+//! to be restarted with the new `--primary`.
+//!
+//! Messages are one per datagram, with numbers in decimal:
+//!
+//! - `OP <epoch> <seq> <expiry> <key>=<value>`: insert number `seq` of the primary, `expiry` being
+//!   when the key expires in milliseconds since the Unix epoch, or `-`
+//! - `SEQ <epoch> <seq>`: the number of the latest insert, sent every second so replicas notice
+//!   when they missed the last ones
+//! - `RESEND <from> <to>`: a replica asking for the inserts it missed, both numbers included
+//! - `SNAP <epoch> <seq> <index> <count> <expiry> <key>=<value>`: key number `index` of the
+//!   `count` keys the primary had after insert `seq`, sent when it no longer has the inserts asked
+//!   for. Without keys, a single `SNAP <epoch> <seq> 0 0` is sent.
+//! - `PROMOTE`
+//!
+//! `epoch` is when the primary started or was promoted, its inserts are numbered from 1 in every
+//! epoch. Replicas start over when it changes, keeping whatever keys they had.
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    future, io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+use crate::store::Store;
+
+/// Room for the largest message: a whole client packet and what comes before it.
+pub(crate) const MAX_MESSAGE_LEN: usize = 2048;
+
+/// Inserts the primary keeps to resend, replicas further behind are sent a snapshot instead.
+const BACKLOG_LEN: usize = 10_000;
+
+/// How long a replica waits for what it asked for before asking again.
+const RESEND_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Snapshots are sent this many keys at a time, pausing in between so the replica can keep up.
+const SNAPSHOT_BATCH_LEN: usize = 64;
+const SNAPSHOT_BATCH_PAUSE: Duration = Duration::from_millis(1);
+
+pub(crate) struct Replication {
+    socket: Arc<UdpSocket>,
+    replicas: Vec<SocketAddr>,
+    /// Where `PROMOTE` is accepted from, besides loopback.
+    admins: Vec<IpAddr>,
+    role: Role,
+}
+
+enum Role {
+    Primary(Primary),
+    Replica(Replica),
+}
+
+struct Primary {
+    epoch: u64,
+    seq: u64,
+    /// The latest inserts, as they were sent.
+    backlog: VecDeque<(u64, Vec<u8>)>,
+}
+
+struct Replica {
+    primary: SocketAddr,
+    epoch: u64,
+    /// Every insert up to this one was applied.
+    applied: u64,
+    /// The latest insert the primary told about.
+    latest: u64,
+    /// Inserts received after one which is missing.
+    pending: BTreeMap<u64, Insert>,
+    snapshot: Option<Snapshot>,
+    /// When missing inserts were last asked for, and the last one asked for.
+    requested: Option<(Instant, u64)>,
+}
+
+struct Snapshot {
+    seq: u64,
+    count: u64,
+    received: HashSet<u64>,
+}
+
+struct Insert {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    expires_at: Option<SystemTime>,
+}
+
+enum Message {
+    Op {
+        epoch: u64,
+        seq: u64,
+        insert: Insert,
+    },
+    Seq {
+        epoch: u64,
+        seq: u64,
+    },
+    Resend {
+        from: u64,
+        to: u64,
+    },
+    Snap {
+        epoch: u64,
+        seq: u64,
+        index: u64,
+        count: u64,
+        insert: Option<Insert>,
+    },
+    Promote,
+}
+
+impl Replication {
+    /// Start replicating if `args` ask for it.
+    pub(crate) async fn from_args(args: &util::Args) -> anyhow::Result<Option<Self>> {
+        let replicas: Vec<SocketAddr> = args
+            .value::<String>("replicas")?
+            .map(|replicas| replicas.split(',').map(str::parse).collect())
+            .transpose()?
+            .unwrap_or_default();
+        let admins: Vec<IpAddr> = args
+            .value::<String>("replication-admins")?
+            .map(|admins| admins.split(',').map(str::parse).collect())
+            .transpose()?
+            .unwrap_or_default();
+        let primary = args.value::<SocketAddr>("primary")?;
+
+        let Some(addr) = args.value::<SocketAddr>("replication")? else {
+            if primary.is_some() || !replicas.is_empty() || !admins.is_empty() {
+                return Err(anyhow!(
+                    "--primary, --replicas and --replication-admins need --replication"
+                ));
+            }
+            return Ok(None);
+        };
+
+        let role = match primary {
+            Some(primary) => {
+                info!("replicating from {primary}");
+                Role::Replica(Replica::new(primary))
+            }
+            None => Role::Primary(Primary::new()),
+        };
+        Ok(Some(Self {
+            socket: Arc::new(UdpSocket::bind(addr).await?),
+            replicas,
+            admins,
+            role,
+        }))
+    }
+
+    /// Whether clients may insert, replicas only take inserts from their primary.
+    pub(crate) fn is_primary(&self) -> bool {
+        matches!(self.role, Role::Primary(_))
+    }
+
+    /// Send a client insert to the replicas.
+    pub(crate) async fn replicate(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<SystemTime>,
+    ) {
+        let Role::Primary(primary) = &mut self.role else {
+            return;
+        };
+
+        primary.seq += 1;
+        let msg = Message::Op {
+            epoch: primary.epoch,
+            seq: primary.seq,
+            insert: Insert {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                expires_at,
+            },
+        }
+        .encode();
+        for replica in &self.replicas {
+            send(&self.socket, &msg, *replica).await;
+        }
+
+        if primary.backlog.len() == BACKLOG_LEN {
+            primary.backlog.pop_front();
+        }
+        primary.backlog.push_back((primary.seq, msg));
+    }
+
+    /// Called every second, to keep replicas in sync.
+    pub(crate) async fn tick(&mut self) {
+        match &mut self.role {
+            Role::Primary(primary) => {
+                let msg = Message::Seq {
+                    epoch: primary.epoch,
+                    seq: primary.seq,
+                }
+                .encode();
+                for replica in &self.replicas {
+                    send(&self.socket, &msg, *replica).await;
+                }
+            }
+            Role::Replica(replica) => replica.request_missing(&self.socket).await,
+        }
+    }
+
+    /// Handle a message received from `addr`.
+    pub(crate) async fn handle(&mut self, db: &mut dyn Store, data: &[u8], addr: SocketAddr) {
+        let Some(msg) = Message::parse(data) else {
+            warn!("ignoring invalid replication message from {addr}: {data:?}");
+            return;
+        };
+
+        match (&mut self.role, msg) {
+            (_, Message::Promote)
+                if !addr.ip().is_loopback() && !self.admins.contains(&addr.ip()) =>
+            {
+                warn!("ignoring PROMOTE from {addr}, which is not a replication admin");
+            }
+            (Role::Replica(replica), Message::Promote) => {
+                info!(
+                    "promoted to primary after insert {} of the old primary",
+                    replica.applied
+                );
+                self.role = Role::Primary(Primary::new());
+            }
+            (Role::Primary(_), Message::Promote) => info!("already the primary"),
+            (Role::Primary(primary), Message::Resend { from, to })
+                if self.replicas.contains(&addr) =>
+            {
+                primary.resend(&self.socket, db, addr, from, to).await;
+            }
+            (Role::Replica(replica), msg) if addr == replica.primary => {
+                replica.handle(db, msg);
+                replica.request_missing(&self.socket).await;
+            }
+            _ => warn!("ignoring unexpected replication message from {addr}"),
+        }
+    }
+}
+
+/// Receive the next replication message, never if replication is disabled.
+pub(crate) async fn recv(
+    replication: Option<&Replication>,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    match replication {
+        Some(replication) => replication.socket.recv_from(buf).await,
+        None => future::pending().await,
+    }
+}
+
+impl Primary {
+    fn new() -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        info!("primary of epoch {epoch}");
+
+        Self {
+            epoch,
+            seq: 0,
+            backlog: VecDeque::new(),
+        }
+    }
+
+    /// Send inserts `from` to `to` again, or a snapshot if some of them are too old.
+    async fn resend(
+        &self,
+        socket: &Arc<UdpSocket>,
+        db: &dyn Store,
+        addr: SocketAddr,
+        from: u64,
+        to: u64,
+    ) {
+        let to = to.min(self.seq);
+        if from == 0 || from > to {
+            return;
+        }
+
+        let oldest = self.backlog.front().map_or(self.seq + 1, |(seq, _)| *seq);
+        if from >= oldest {
+            let skip = (from - oldest) as usize;
+            let take = (to - from + 1) as usize;
+            for (_, msg) in self.backlog.iter().skip(skip).take(take) {
+                send(socket, msg, addr).await;
+            }
+            return;
+        }
+
+        let count = db.entries().count() as u64;
+        info!("sending a snapshot of {count} keys to {addr}, which missed insert {from}");
+        let mut msgs = db
+            .entries()
+            .enumerate()
+            .map(|(index, (key, entry))| {
+                Message::Snap {
+                    epoch: self.epoch,
+                    seq: self.seq,
+                    index: index as u64,
+                    count,
+                    insert: Some(Insert {
+                        key: key.to_vec(),
+                        value: entry.value.clone(),
+                        expires_at: entry.expires_at,
+                    }),
+                }
+                .encode()
+            })
+            .collect::<Vec<_>>();
+        if msgs.is_empty() {
+            let msg = Message::Snap {
+                epoch: self.epoch,
+                seq: self.seq,
+                index: 0,
+                count: 0,
+                insert: None,
+            };
+            msgs.push(msg.encode());
+        }
+
+        // in the background, a big snapshot takes a while with the pauses
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            for batch in msgs.chunks(SNAPSHOT_BATCH_LEN) {
+                for msg in batch {
+                    send(&socket, msg, addr).await;
+                }
+                tokio::time::sleep(SNAPSHOT_BATCH_PAUSE).await;
+            }
+        });
+    }
+}
+
+impl Replica {
+    fn new(primary: SocketAddr) -> Self {
+        Self {
+            primary,
+            epoch: 0,
+            applied: 0,
+            latest: 0,
+            pending: BTreeMap::new(),
+            snapshot: None,
+            requested: None,
+        }
+    }
+
+    fn handle(&mut self, db: &mut dyn Store, msg: Message) {
+        match msg {
+            Message::Op { epoch, seq, insert } => {
+                if !self.follow(epoch) {
+                    return;
+                }
+                self.latest = self.latest.max(seq);
+                if seq > self.applied {
+                    self.pending.insert(seq, insert);
+                }
+                self.apply_pending(db);
+            }
+            Message::Seq { epoch, seq } => {
+                if self.follow(epoch) {
+                    self.latest = self.latest.max(seq);
+                }
+            }
+            Message::Snap {
+                epoch,
+                seq,
+                index,
+                count,
+                insert,
+            } => {
+                if !self.follow(epoch) || seq <= self.applied {
+                    return;
+                }
+                self.latest = self.latest.max(seq);
+
+                let snapshot = match &mut self.snapshot {
+                    Some(snapshot) if snapshot.seq == seq => snapshot,
+                    snapshot => snapshot.insert(Snapshot {
+                        seq,
+                        count,
+                        received: HashSet::new(),
+                    }),
+                };
+                if let Some(insert) = insert {
+                    apply(db, insert);
+                    snapshot.received.insert(index);
+                }
+                // the rest of the snapshot is likely on its way
+                self.requested = Some((Instant::now(), seq));
+                if snapshot.received.len() as u64 >= snapshot.count {
+                    info!("caught up to insert {seq} with a snapshot of {count} keys");
+                    self.snapshot = None;
+                    self.applied = seq;
+                    self.pending = self.pending.split_off(&(seq + 1));
+                    self.apply_pending(db);
+                }
+            }
+            Message::Resend { .. } | Message::Promote => {}
+        }
+    }
+
+    /// Whether messages of `epoch` are to be followed, starting over if it is a new one.
+    fn follow(&mut self, epoch: u64) -> bool {
+        if epoch > self.epoch {
+            info!("following epoch {epoch} of the primary");
+            *self = Self {
+                epoch,
+                ..Self::new(self.primary)
+            };
+        }
+        epoch == self.epoch
+    }
+
+    fn apply_pending(&mut self, db: &mut dyn Store) {
+        while let Some(insert) = self.pending.remove(&(self.applied + 1)) {
+            apply(db, insert);
+            self.applied += 1;
+        }
+    }
+
+    /// Ask for the first inserts missing, unless they were asked for recently.
+    async fn request_missing(&mut self, socket: &UdpSocket) {
+        if self.applied >= self.latest {
+            return;
+        }
+
+        let from = self.applied + 1;
+        let to = self
+            .pending
+            .keys()
+            .next()
+            .map_or(self.latest, |first| first - 1);
+        if self
+            .requested
+            .is_some_and(|(at, requested_to)| from <= requested_to && at.elapsed() < RESEND_TIMEOUT)
+        {
+            return;
+        }
+
+        info!("missing inserts {from} to {to}, asking for them");
+        self.requested = Some((Instant::now(), to));
+        send(socket, &Message::Resend { from, to }.encode(), self.primary).await;
+    }
+}
+
+fn apply(db: &mut dyn Store, insert: Insert) {
+    crate::insert(db, &insert.key, &insert.value, insert.expires_at);
+}
+
+async fn send(socket: &UdpSocket, msg: &[u8], addr: SocketAddr) {
+    if let Err(e) = socket.send_to(msg, addr).await {
+        warn!("failed to send replication message to {addr}: {e}");
+    }
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let (header, insert) = match self {
+            Self::Op { epoch, seq, insert } => (format!("OP {epoch} {seq}"), Some(insert)),
+            Self::Seq { epoch, seq } => (format!("SEQ {epoch} {seq}"), None),
+            Self::Resend { from, to } => (format!("RESEND {from} {to}"), None),
+            Self::Snap {
+                epoch,
+                seq,
+                index,
+                count,
+                insert,
+            } => (
+                format!("SNAP {epoch} {seq} {index} {count}"),
+                insert.as_ref(),
+            ),
+            Self::Promote => ("PROMOTE".to_string(), None),
+        };
+
+        let mut msg = header.into_bytes();
+        if let Some(insert) = insert {
+            let expiry = match insert.expires_at {
+                Some(expires_at) => expires_at
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis() as u64)
+                    .to_string(),
+                None => "-".to_string(),
+            };
+            msg.extend_from_slice(format!(" {expiry} ").as_bytes());
+            msg.extend_from_slice(&insert.key);
+            msg.push(b'=');
+            msg.extend_from_slice(&insert.value);
+        }
+        msg
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (kind, rest) = match data.iter().position(|b| *b == b' ') {
+            Some(sep) => (&data[..sep], &data[sep + 1..]),
+            None => (data, &[][..]),
+        };
+
+        match kind {
+            b"OP" => {
+                let [epoch, seq, insert] = fields(rest)?;
+                Some(Self::Op {
+                    epoch: number(epoch)?,
+                    seq: number(seq)?,
+                    insert: parse_insert(insert)?,
+                })
+            }
+            b"SEQ" => {
+                let [epoch, seq] = fields(rest)?;
+                Some(Self::Seq {
+                    epoch: number(epoch)?,
+                    seq: number(seq)?,
+                })
+            }
+            b"RESEND" => {
+                let [from, to] = fields(rest)?;
+                Some(Self::Resend {
+                    from: number(from)?,
+                    to: number(to)?,
+                })
+            }
+            b"SNAP" => {
+                let mut parts = rest.splitn(5, |b| *b == b' ');
+                let epoch = number(parts.next()?)?;
+                let seq = number(parts.next()?)?;
+                let index = number(parts.next()?)?;
+                let count = number(parts.next()?)?;
+                let insert = match (count, parts.next()) {
+                    (0, None) => None,
+                    (1.., Some(insert)) => Some(parse_insert(insert)?),
+                    _ => return None,
+                };
+                Some(Self::Snap {
+                    epoch,
+                    seq,
+                    index,
+                    count,
+                    insert,
+                })
+            }
+            b"PROMOTE" if rest.is_empty() => Some(Self::Promote),
+            _ => None,
+        }
+    }
+}
+
+/// Exactly `N` fields separated by spaces, the last one taking the rest.
+fn fields<const N: usize>(data: &[u8]) -> Option<[&[u8]; N]> {
+    let parts = data.splitn(N, |b| *b == b' ').collect::<Vec<_>>();
+    parts.try_into().ok()
+}
+
+fn number(field: &[u8]) -> Option<u64> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+/// `<expiry> <key>=<value>`
+fn parse_insert(data: &[u8]) -> Option<Insert> {
+    let [expiry, pair] = fields(data)?;
+    let expires_at = match expiry {
+        b"-" => None,
+        millis => Some(UNIX_EPOCH + Duration::from_millis(number(millis)?)),
+    };
+    let sep = pair.iter().position(|b| *b == b'=')?;
+    Some(Insert {
+        key: pair[..sep].to_vec(),
+        value: pair[sep + 1..].to_vec(),
+        expires_at,
+    })
+}
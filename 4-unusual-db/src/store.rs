@@ -0,0 +1,508 @@
+//! Where the keys are kept, chosen with `--store=<spec>`.
+//!
+//! - `memory` (the default): in a map, lost on restart
+//! - `log:<path>`: also appended to a log file at `path`, read back on startup
+//!
+//! Every record of the log is `<crc32><key len><value len>[<expires at>]<key><value>`, with the
+//! numbers as little endian `u32`s and the checksum covering everything after it. Keys which
+//! expire have the top bit of the value length set, and the time they expire at as a little endian
+//! `u64` of milliseconds since the Unix epoch. A record cut short by a crash, or otherwise
+//! damaged, ends the log: it is dropped with everything after it when the log is opened. Once the
+//! log is more than twice as big as the records still in use, it is compacted by writing those to
+//! `<path>.compact`, and renaming that over the log.
+//!
+//! Records are written with a single `write` and without buffering, so they survive the process
+//! being killed. Getting them to the disk is left to the OS.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use tracing::{info, warn};
+
+#[derive(Debug)]
+pub(crate) struct Entry {
+    pub(crate) value: Vec<u8>,
+    /// `None` for keys which are kept until they are overwritten.
+    pub(crate) expires_at: Option<SystemTime>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+pub(crate) trait Store: Send {
+    /// The entry of `key`, unless there is none or it expired.
+    fn get(&self, key: &[u8]) -> Option<&Entry>;
+
+    fn insert(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<SystemTime>,
+    ) -> io::Result<()>;
+
+    /// Remove every key which expired.
+    fn sweep(&mut self);
+
+    /// Every key which didn't expire, with its entry.
+    fn entries(&self) -> Box<dyn Iterator<Item = (&[u8], &Entry)> + '_>;
+}
+
+/// Parse the value of `--store`.
+pub(crate) fn from_spec(spec: &str) -> anyhow::Result<Box<dyn Store>> {
+    match spec.split_once(':') {
+        None if spec == "memory" => Ok(Box::new(Memory::default())),
+        Some(("log", path)) => Ok(Box::new(Log::open(Path::new(path))?)),
+        _ => Err(anyhow!(
+            "unknown store {spec:?}, expected memory or log:<path>"
+        )),
+    }
+}
+
+#[derive(Default)]
+struct Memory {
+    map: HashMap<Vec<u8>, Entry>,
+    /// Keys which expire, soonest first.
+    expiries: BTreeSet<(SystemTime, Vec<u8>)>,
+}
+
+impl Memory {
+    /// Returns the previous entry of `key`.
+    fn set(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+        let expires_at = entry.expires_at;
+        let old = self.map.insert(key.to_vec(), entry);
+        if let Some(old_expires_at) = old.as_ref().and_then(|old| old.expires_at) {
+            self.expiries.remove(&(old_expires_at, key.to_vec()));
+        }
+        if let Some(expires_at) = expires_at {
+            self.expiries.insert((expires_at, key.to_vec()));
+        }
+        old
+    }
+
+    /// Remove every key which expired by `now`, and return them.
+    fn remove_expired(&mut self, now: SystemTime) -> Vec<(Vec<u8>, Entry)> {
+        let mut removed = Vec::new();
+        while let Some((expires_at, _)) = self.expiries.first() {
+            if *expires_at > now {
+                break;
+            }
+            let Some((_, key)) = self.expiries.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.map.remove(&key) {
+                removed.push((key, entry));
+            }
+        }
+        removed
+    }
+}
+
+impl Store for Memory {
+    fn get(&self, key: &[u8]) -> Option<&Entry> {
+        let entry = self.map.get(key)?;
+        (!entry.is_expired(SystemTime::now())).then_some(entry)
+    }
+
+    fn insert(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<SystemTime>,
+    ) -> io::Result<()> {
+        let entry = Entry {
+            value: value.to_vec(),
+            expires_at,
+        };
+        self.set(key, entry);
+        Ok(())
+    }
+
+    fn sweep(&mut self) {
+        self.remove_expired(SystemTime::now());
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&[u8], &Entry)> + '_> {
+        let now = SystemTime::now();
+        Box::new(
+            self.map
+                .iter()
+                .filter(move |(_, entry)| !entry.is_expired(now))
+                .map(|(key, entry)| (key.as_slice(), entry)),
+        )
+    }
+}
+
+/// Bytes before the key of a record, without the expiry.
+const HEADER_LEN: usize = 12;
+
+/// Set in the value length of records with an expiry.
+const EXPIRES_FLAG: u32 = 1 << 31;
+
+/// Longest key or value read back, anything longer is damage rather than a record.
+const MAX_FIELD_LEN: usize = 64 * 1024;
+
+/// Don't bother compacting logs smaller than this.
+const MIN_COMPACT_LEN: u64 = 1024 * 1024;
+
+struct Log {
+    memory: Memory,
+    path: PathBuf,
+    file: File,
+    /// Size of the log.
+    len: u64,
+    /// Size the log would have with only the records in `memory`.
+    live: u64,
+}
+
+impl Log {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        // a compaction that didn't finish, the log it would have replaced is still complete
+        let compact = compact_path(path);
+        if compact.exists() {
+            warn!("removing unfinished compaction {}", compact.display());
+            fs::remove_file(&compact)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut memory = Memory::default();
+        let mut pos = 0;
+        while let Some((key, entry, len)) = decode(&data[pos..]) {
+            memory.set(key, entry);
+            pos += len;
+        }
+        // expired keys are gone, even if an older record of them didn't expire
+        memory.remove_expired(SystemTime::now());
+
+        if pos < data.len() {
+            warn!(
+                "dropping {} damaged bytes at the end of {}",
+                data.len() - pos,
+                path.display()
+            );
+            file.set_len(pos as u64)?;
+        }
+        info!("opened {} with {} keys", path.display(), memory.map.len());
+
+        let live = memory
+            .map
+            .iter()
+            .map(|(key, entry)| record_len(key, entry))
+            .sum();
+        Ok(Self {
+            memory,
+            path: path.to_path_buf(),
+            file,
+            len: pos as u64,
+            live,
+        })
+    }
+
+    /// Compact if enough of the log is dead records. A failed compaction leaves the log as it
+    /// was, only bigger than it has to be, so it is logged rather than returned.
+    fn compact_if_needed(&mut self) {
+        if self.len > MIN_COMPACT_LEN && self.len > 2 * self.live {
+            if let Err(e) = self.compact() {
+                warn!("failed to compact {}: {e}", self.path.display());
+            }
+        }
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        let compact = compact_path(&self.path);
+        let mut file = File::create(&compact)?;
+        let mut len = 0;
+        for (key, entry) in &self.memory.map {
+            let record = encode(key, entry);
+            file.write_all(&record)?;
+            len += record.len() as u64;
+        }
+        file.sync_all()?;
+        // opened before the rename, so nothing can fail once the log was replaced
+        let appender = OpenOptions::new().append(true).open(&compact)?;
+        fs::rename(&compact, &self.path)?;
+
+        info!(
+            "compacted {} from {} to {len} bytes",
+            self.path.display(),
+            self.len
+        );
+        self.file = appender;
+        self.len = len;
+        self.live = len;
+        Ok(())
+    }
+}
+
+impl Store for Log {
+    fn get(&self, key: &[u8]) -> Option<&Entry> {
+        self.memory.get(key)
+    }
+
+    fn insert(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<SystemTime>,
+    ) -> io::Result<()> {
+        let entry = Entry {
+            value: value.to_vec(),
+            expires_at,
+        };
+        let record = encode(key, &entry);
+        if let Err(e) = self.file.write_all(&record) {
+            // whatever part was written would hide every record appended after it
+            let _ = self.file.set_len(self.len);
+            return Err(e);
+        }
+        self.len += record.len() as u64;
+        self.live += record.len() as u64;
+
+        if let Some(old) = self.memory.set(key, entry) {
+            self.live -= record_len(key, &old);
+        }
+
+        self.compact_if_needed();
+        Ok(())
+    }
+
+    fn sweep(&mut self) {
+        // the records of expired keys say they expired, so there is nothing to write
+        for (key, entry) in self.memory.remove_expired(SystemTime::now()) {
+            self.live -= record_len(&key, &entry);
+        }
+        self.compact_if_needed();
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&[u8], &Entry)> + '_> {
+        self.memory.entries()
+    }
+}
+
+fn compact_path(path: &Path) -> PathBuf {
+    let mut compact = path.as_os_str().to_owned();
+    compact.push(".compact");
+    PathBuf::from(compact)
+}
+
+fn record_len(key: &[u8], entry: &Entry) -> u64 {
+    let expiry_len = if entry.expires_at.is_some() { 8 } else { 0 };
+    (HEADER_LEN + expiry_len + key.len() + entry.value.len()) as u64
+}
+
+fn encode(key: &[u8], entry: &Entry) -> Vec<u8> {
+    let mut record = Vec::with_capacity(record_len(key, entry) as usize);
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    match entry.expires_at {
+        Some(expires_at) => {
+            let value_len = entry.value.len() as u32 | EXPIRES_FLAG;
+            let millis = expires_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64);
+            record.extend_from_slice(&value_len.to_le_bytes());
+            record.extend_from_slice(&millis.to_le_bytes());
+        }
+        None => record.extend_from_slice(&(entry.value.len() as u32).to_le_bytes()),
+    }
+    record.extend_from_slice(key);
+    record.extend_from_slice(&entry.value);
+
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// The key and entry of the record at the start of `data` with its length, or `None` if there
+/// is no complete and intact record.
+fn decode(data: &[u8]) -> Option<(&[u8], Entry, usize)> {
+    let header = data.get(..HEADER_LEN)?;
+    let crc = u32::from_le_bytes(header[0..4].try_into().ok()?);
+    let key_len = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+    let value_len = u32::from_le_bytes(header[8..12].try_into().ok()?);
+    let (value_len, expiry_len) = match value_len & EXPIRES_FLAG {
+        0 => (value_len as usize, 0),
+        _ => ((value_len & !EXPIRES_FLAG) as usize, 8),
+    };
+    if key_len > MAX_FIELD_LEN || value_len > MAX_FIELD_LEN {
+        return None;
+    }
+
+    let len = HEADER_LEN + expiry_len + key_len + value_len;
+    let record = data.get(..len)?;
+    if crc32fast::hash(&record[4..]) != crc {
+        return None;
+    }
+
+    let (expiry, rest) = record[HEADER_LEN..].split_at(expiry_len);
+    let expires_at = match expiry.try_into() {
+        Ok(millis) => Some(UNIX_EPOCH + Duration::from_millis(u64::from_le_bytes(millis))),
+        Err(_) => None,
+    };
+    let (key, value) = rest.split_at(key_len);
+    let entry = Entry {
+        value: value.to_vec(),
+        expires_at,
+    };
+    Some((key, entry, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path for a log of its own, with nothing left from earlier runs.
+    fn log_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("unusual-db-{}-{name}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(compact_path(&path));
+        path
+    }
+
+    fn value(store: &dyn Store, key: &[u8]) -> Option<Vec<u8>> {
+        store.get(key).map(|entry| entry.value.clone())
+    }
+
+    /// Append `key`s with their own name as value.
+    fn write(path: &Path, keys: &[&[u8]]) {
+        let mut log = Log::open(path).unwrap();
+        for key in keys {
+            log.insert(key, key, None).unwrap();
+        }
+    }
+
+    fn record(key: &[u8]) -> u64 {
+        record_len(
+            key,
+            &Entry {
+                value: key.to_vec(),
+                expires_at: None,
+            },
+        )
+    }
+
+    #[test]
+    fn reopens_with_every_key() {
+        let path = log_path("reopen");
+        write(&path, &[b"a", b"b"]);
+
+        let mut log = Log::open(&path).unwrap();
+        log.insert(b"a", b"new", None).unwrap();
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        log.insert(b"ttl", b"x", Some(expires_at)).unwrap();
+        drop(log);
+
+        let log = Log::open(&path).unwrap();
+        assert_eq!(value(&log, b"a"), Some(b"new".to_vec()));
+        assert_eq!(value(&log, b"b"), Some(b"b".to_vec()));
+        let kept = log.get(b"ttl").unwrap().expires_at.unwrap();
+        let millis = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        assert_eq!(millis(kept), millis(expires_at));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_prefix_of_truncated_log() {
+        let path = log_path("truncated");
+        write(&path, &[b"a", b"bb"]);
+
+        // cut in the middle of the second record
+        let intact = record(b"a");
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(intact + 5).unwrap();
+        drop(file);
+
+        let log = Log::open(&path).unwrap();
+        assert_eq!(value(&log, b"a"), Some(b"a".to_vec()));
+        assert_eq!(value(&log, b"bb"), None);
+        assert_eq!(fs::metadata(&path).unwrap().len(), intact);
+        drop(log);
+
+        write(&path, &[b"c"]);
+        let log = Log::open(&path).unwrap();
+        assert_eq!(value(&log, b"a"), Some(b"a".to_vec()));
+        assert_eq!(value(&log, b"c"), Some(b"c".to_vec()));
+        assert_eq!(log.entries().count(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drops_everything_after_damaged_record() {
+        let path = log_path("damaged");
+        write(&path, &[b"a", b"b", b"c"]);
+
+        // flip a byte of the checksum of the second record
+        let intact = record(b"a");
+        let mut data = fs::read(&path).unwrap();
+        data[intact as usize] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        let log = Log::open(&path).unwrap();
+        assert_eq!(value(&log, b"a"), Some(b"a".to_vec()));
+        assert_eq!(value(&log, b"b"), None);
+        assert_eq!(value(&log, b"c"), None);
+        assert_eq!(fs::metadata(&path).unwrap().len(), intact);
+        drop(log);
+
+        write(&path, &[b"d"]);
+        let log = Log::open(&path).unwrap();
+        assert_eq!(value(&log, b"a"), Some(b"a".to_vec()));
+        assert_eq!(value(&log, b"d"), Some(b"d".to_vec()));
+        assert_eq!(log.entries().count(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn removes_unfinished_compaction() {
+        let path = log_path("compaction");
+        write(&path, &[b"a"]);
+        let compact = compact_path(&path);
+        fs::write(&compact, b"half a compaction").unwrap();
+
+        let log = Log::open(&path).unwrap();
+        assert!(!compact.exists());
+        assert_eq!(value(&log, b"a"), Some(b"a".to_vec()));
+        drop(log);
+
+        write(&path, &[b"b"]);
+        let log = Log::open(&path).unwrap();
+        assert_eq!(log.entries().count(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compacts_overwritten_keys() {
+        let path = log_path("compact");
+        let mut log = Log::open(&path).unwrap();
+        let big = vec![b'x'; 1024];
+        // a few megabytes of records of the same two keys
+        for i in 0..3000 {
+            let key: &[u8] = if i % 2 == 0 { b"even" } else { b"odd" };
+            log.insert(key, &big, None).unwrap();
+        }
+        // compacted as soon as it grew past the minimum
+        assert!(log.len <= MIN_COMPACT_LEN + record_len(b"even", log.get(b"even").unwrap()));
+        drop(log);
+
+        let log = Log::open(&path).unwrap();
+        assert_eq!(log.entries().count(), 2);
+        assert_eq!(log.get(b"odd").unwrap().value, big);
+        fs::remove_file(&path).unwrap();
+    }
+}
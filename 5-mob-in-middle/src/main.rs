@@ -63,12 +63,12 @@ async fn handle_stream(mut stream: TcpStream, _: ()) -> anyhow::Result<()> {
                 if n == 0 {
                     break;
                 }
-                client_msg_buf.extend_from_slice(&client_buf);
+                client_msg_buf.extend_from_slice(client_buf);
                 client_buf.clear();
                 if *client_msg_buf.last().unwrap() != b'\n' {
                     continue;
                 }
-                upstream_writer.write_all(&replace_wallet(&client_msg_buf)).await?;
+                upstream_writer.write_all(&replace_wallet(client_msg_buf)).await?;
                 client_msg_buf.clear();
             }
             res = upstream_lines.read_until(b'\n', upstream_buf) => {
@@ -76,7 +76,7 @@ async fn handle_stream(mut stream: TcpStream, _: ()) -> anyhow::Result<()> {
                 if n == 0 {
                     break;
                 }
-                client_writer.write_all(&replace_wallet(&upstream_buf)).await?;
+                client_writer.write_all(&replace_wallet(upstream_buf)).await?;
                 upstream_buf.clear();
             }
         }
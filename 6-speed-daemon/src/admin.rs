@@ -0,0 +1,163 @@
+//! Line based operator interface, enabled with `--admin=<addr>`.
+//!
+//! Every request is a single line, and every response is zero or more lines of output followed by
+//! either `ok` or `error: <reason>`. Send `help` for the list of commands.
+
+use std::{fmt::Write as _, sync::Arc};
+
+use anyhow::anyhow;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{Road, State, Ticket, TicketId, SECS_IN_A_DAY};
+
+const HELP: &str = "\
+cameras                   list connected cameras
+dispatchers               list connected dispatchers and their roads
+pending [road]            list tickets waiting for a dispatcher
+plate <plate>             show observations and ticketed days of a car
+reissue <ticket id>       deliver a pending ticket to a dispatcher now
+cancel <ticket id>        drop a pending ticket
+stats                     show counters
+";
+
+pub(crate) async fn handle_stream(mut stream: TcpStream, state: State) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let mut out = String::new();
+
+        match run_command(line.trim(), &state, &mut out).await {
+            Ok(()) => out.push_str("ok\n"),
+            Err(e) => writeln!(out, "error: {e}")?,
+        }
+
+        writer.write_all(out.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+async fn run_command(line: &str, state: &State, out: &mut String) -> anyhow::Result<()> {
+    let mut words = line.split_whitespace();
+
+    match words.next() {
+        None => Ok(()),
+        Some("help") => {
+            out.push_str(HELP);
+            Ok(())
+        }
+        Some("cameras") => cameras(state, out).await,
+        Some("dispatchers") => dispatchers(state, out).await,
+        Some("pending") => pending(words.next().map(str::parse).transpose()?, state, out).await,
+        Some("plate") => {
+            let plate = words
+                .next()
+                .ok_or_else(|| anyhow!("usage: plate <plate>"))?;
+            plate_info(plate.as_bytes(), state, out).await
+        }
+        Some("reissue") => {
+            let ticket_id = parse_ticket_id(words.next())?;
+            state.dispatchers.write().await.reissue(ticket_id).await
+        }
+        Some("cancel") => {
+            let ticket_id = parse_ticket_id(words.next())?;
+            let ticket = state.dispatchers.write().await.cancel(ticket_id)?;
+            write_ticket(&ticket, out)
+        }
+        Some("stats") => {
+            let heartbeats = state.heartbeat.metrics.snapshot();
+            writeln!(out, "rejected_plates={}", state.plates.rejected())?;
+            writeln!(
+                out,
+                "heartbeats={} late_heartbeats={} total_lateness_ms={} max_lateness_ms={}",
+                heartbeats.beats,
+                heartbeats.late_beats,
+                heartbeats.total_lateness.as_millis(),
+                heartbeats.max_lateness.as_millis(),
+            )?;
+            Ok(())
+        }
+        Some(command) => Err(anyhow!("unknown command {command:?}, try help")),
+    }
+}
+
+fn parse_ticket_id(word: Option<&str>) -> anyhow::Result<TicketId> {
+    word.ok_or_else(|| anyhow!("missing ticket id"))?
+        .parse()
+        .map_err(|e| anyhow!("invalid ticket id: {e}"))
+}
+
+async fn cameras(state: &State, out: &mut String) -> anyhow::Result<()> {
+    for (addr, camera) in state.cameras.lock().await.iter() {
+        writeln!(
+            out,
+            "camera {addr} road={} mile={} limit={}",
+            camera.road, camera.mile, camera.limit
+        )?;
+    }
+    Ok(())
+}
+
+async fn dispatchers(state: &State, out: &mut String) -> anyhow::Result<()> {
+    for (id, dispatcher) in state.dispatchers.read().await.dispatchers.iter() {
+        writeln!(
+            out,
+            "dispatcher {id} {} roads={}",
+            dispatcher.addr,
+            join(&dispatcher.roads)
+        )?;
+    }
+    Ok(())
+}
+
+async fn pending(road: Option<Road>, state: &State, out: &mut String) -> anyhow::Result<()> {
+    let lock = state.dispatchers.read().await;
+
+    for (pending_road, tickets) in lock.pending_tickets.iter() {
+        if road.is_some_and(|road| road != *pending_road) {
+            continue;
+        }
+        for ticket in tickets {
+            write_ticket(ticket, out)?;
+        }
+    }
+    Ok(())
+}
+
+async fn plate_info(plate: &[u8], state: &State, out: &mut String) -> anyhow::Result<()> {
+    let plate = state.plates.normalize(Arc::new(plate.to_vec()));
+
+    let lock = state.cars.lock().await;
+    let car = lock
+        .get(&plate)
+        .ok_or_else(|| anyhow!("no observations for this plate"))?;
+
+    for (road, entries) in car.roads.iter() {
+        for (timestamp, mile) in entries {
+            writeln!(
+                out,
+                "observation road={road} mile={mile} timestamp={timestamp} day={}",
+                timestamp / SECS_IN_A_DAY
+            )?;
+        }
+    }
+    writeln!(out, "ticketed days={}", join(car.tickets.iter()))?;
+    Ok(())
+}
+
+fn write_ticket(ticket: &Ticket, out: &mut String) -> anyhow::Result<()> {
+    writeln!(out, "{ticket}")?;
+    Ok(())
+}
+
+fn join<T: ToString>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
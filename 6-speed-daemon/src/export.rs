@@ -0,0 +1,260 @@
+//! Audit trail of issued tickets, enabled with `--export=<format>:<path>`.
+//!
+//! `format` is either `jsonl` or `csv`, and a `path` of `-` writes to stdout. Files are appended
+//! to, so restarts keep the previous records. A record is written every time the delivery status
+//! of a ticket changes, so a ticket issued while no dispatcher was connected shows up first as
+//! `pending` and later as `dispatched` or `cancelled`, with the same id. Ids carry on from the
+//! highest one already in the file, so they stay unique across restarts.
+//!
+//! Records are written on a blocking thread, fed through a channel, so exporting a ticket never
+//! waits for the sink.
+
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::{Ticket, TicketId};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Status {
+    /// Handed to a connected dispatcher.
+    Dispatched,
+    /// Waiting for a dispatcher responsible for the road to connect.
+    Pending,
+    /// Dropped by an operator while pending.
+    Cancelled,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Dispatched => "dispatched",
+            Status::Pending => "pending",
+            Status::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TicketRecord {
+    id: TicketId,
+    plate: String,
+    road: u16,
+    mile1: u16,
+    timestamp1: u32,
+    mile2: u16,
+    timestamp2: u32,
+    speed: f64,
+    status: Status,
+    recorded_at: u64,
+}
+
+impl TicketRecord {
+    pub(crate) fn new(ticket: &Ticket, status: Status) -> Self {
+        Self {
+            id: ticket.id,
+            plate: String::from_utf8_lossy(&ticket.plate).into_owned(),
+            road: ticket.road,
+            mile1: ticket.mile1,
+            timestamp1: ticket.timestamp1,
+            mile2: ticket.mile2,
+            timestamp2: ticket.timestamp2,
+            speed: ticket.speed as f64 / 100.0,
+            status,
+            recorded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        }
+    }
+}
+
+pub(crate) trait TicketSink: Send + Sync {
+    fn write(&mut self, record: &TicketRecord) -> io::Result<()>;
+}
+
+/// Hands records to the thread writing them to a sink.
+pub(crate) struct Exporter(mpsc::UnboundedSender<TicketRecord>);
+
+impl Exporter {
+    /// Start writing to `sink`, until the exporter is dropped.
+    pub(crate) fn spawn(mut sink: Box<dyn TicketSink>) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<TicketRecord>();
+        tokio::task::spawn_blocking(move || {
+            while let Some(record) = rx.blocking_recv() {
+                if let Err(e) = sink.write(&record) {
+                    error!("failed to export ticket {}: {e}", record.id);
+                }
+            }
+        });
+        Self(tx)
+    }
+
+    pub(crate) fn export(&self, ticket: &Ticket, status: Status) {
+        // the writer only stops once the exporter is dropped
+        let _ = self.0.send(TicketRecord::new(ticket, status));
+    }
+}
+
+/// Parse the value of `--export`, returning the sink and the first ticket id it hasn't seen yet.
+pub(crate) fn from_spec(spec: &str) -> anyhow::Result<(Box<dyn TicketSink>, TicketId)> {
+    let (format, path) = spec
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected <format>:<path> for --export, got {spec:?}"))?;
+    if !matches!(format, "jsonl" | "csv") {
+        return Err(anyhow!(
+            "unknown export format {format:?}, expected jsonl or csv"
+        ));
+    }
+
+    let mut next_id = 0;
+    let (writer, is_empty): (Box<dyn Write + Send + Sync>, bool) = if path == "-" {
+        (Box::new(io::stdout()), true)
+    } else {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+        next_id = first_unused_id(&file, format)?;
+        (Box::new(BufWriter::new(file)), is_empty)
+    };
+
+    let sink: Box<dyn TicketSink> = match format {
+        "jsonl" => Box::new(JsonLines(writer)),
+        _ => Box::new(Csv {
+            writer,
+            header_written: !is_empty,
+        }),
+    };
+    Ok((sink, next_id))
+}
+
+/// One past the highest id in an existing export, skipping lines that don't parse, like the
+/// CSV header or a record cut short by a crash.
+fn first_unused_id(file: &File, format: &str) -> io::Result<TicketId> {
+    #[derive(Deserialize)]
+    struct RecordId {
+        id: TicketId,
+    }
+
+    let mut next_id: TicketId = 0;
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line?;
+        let id = match format {
+            "jsonl" => serde_json::from_slice::<RecordId>(&line).ok().map(|r| r.id),
+            _ => line
+                .split(|b| *b == b',')
+                .next()
+                .and_then(|id| std::str::from_utf8(id).ok()?.parse().ok()),
+        };
+        if let Some(id) = id {
+            next_id = next_id.max(id.saturating_add(1));
+        }
+    }
+    Ok(next_id)
+}
+
+struct JsonLines<W>(W);
+
+impl<W: Write + Send + Sync> TicketSink for JsonLines<W> {
+    fn write(&mut self, record: &TicketRecord) -> io::Result<()> {
+        serde_json::to_writer(&mut self.0, record)?;
+        self.0.write_all(b"\n")?;
+        self.0.flush()
+    }
+}
+
+struct Csv<W> {
+    writer: W,
+    header_written: bool,
+}
+
+const CSV_HEADER: &str =
+    "id,plate,road,mile1,timestamp1,mile2,timestamp2,speed,status,recorded_at\n";
+
+impl<W: Write + Send + Sync> TicketSink for Csv<W> {
+    fn write(&mut self, record: &TicketRecord) -> io::Result<()> {
+        if !self.header_written {
+            self.writer.write_all(CSV_HEADER.as_bytes())?;
+            self.header_written = true;
+        }
+
+        writeln!(
+            self.writer,
+            "{},{},{},{},{},{},{},{:.2},{},{}",
+            record.id,
+            csv_escape(&record.plate),
+            record.road,
+            record.mile1,
+            record.timestamp1,
+            record.mile2,
+            record.timestamp2,
+            record.speed,
+            record.status.as_str(),
+            record.recorded_at,
+        )?;
+        self.writer.flush()
+    }
+}
+
+fn csv_escape(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn ticket(id: TicketId) -> Ticket {
+        Ticket {
+            id,
+            plate: Arc::new(b"UN1X".to_vec()),
+            road: 66,
+            mile1: 100,
+            timestamp1: 123456,
+            mile2: 110,
+            timestamp2: 123816,
+            speed: 10000,
+        }
+    }
+
+    #[test]
+    fn ids_carry_on_after_restart() {
+        for format in ["jsonl", "csv"] {
+            let path = std::env::temp_dir().join(format!(
+                "speed-daemon-export-{}.{format}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            let spec = format!("{format}:{}", path.display());
+
+            let (mut sink, next_id) = from_spec(&spec).unwrap();
+            assert_eq!(next_id, 0);
+            for id in [0, 2, 1] {
+                sink.write(&TicketRecord::new(&ticket(id), Status::Pending))
+                    .unwrap();
+            }
+            drop(sink);
+
+            let (_, next_id) = from_spec(&spec).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(next_id, 3, "{format}");
+        }
+    }
+}
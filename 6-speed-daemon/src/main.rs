@@ -1,24 +1,73 @@
-use std::{collections::BTreeMap, io::IoSlice, mem, sync::Arc, time::Duration};
+mod admin;
+mod export;
+mod plate;
+mod replay;
+
+use std::{
+    collections::BTreeMap, fmt, mem, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration,
+};
 
 use anyhow::anyhow;
-use futures::future;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
     sync::{mpsc, Mutex, RwLock},
-    time,
 };
-use tracing::error;
+use tracing::{error, info, warn};
+use util::heartbeat::{Heartbeat, HeartbeatConfig};
+
+use export::{Exporter, Status};
+use plate::{OnReject, PlateRules};
+use replay::{Event, Recorder, Recording};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    util::accept_loop_with_env(handle_stream, State::default()).await
+    util::init_tracing();
+
+    let args = util::Args::from_env();
+
+    let mut dispatchers = DispatchersMap::default();
+    if let Some(spec) = args.value::<String>("export")? {
+        let (sink, next_id) = export::from_spec(&spec)?;
+        dispatchers.last_ticket_id = next_id;
+        dispatchers.export = Some(Exporter::spawn(sink));
+    }
+    let state = State {
+        dispatchers: Arc::new(RwLock::new(dispatchers)),
+        recorder: args
+            .value::<PathBuf>("record")?
+            .map(|path| Recorder::create(&path).map(Arc::new))
+            .transpose()?,
+        plates: Arc::new(PlateRules::from_args(&args)?),
+        heartbeat: HeartbeatConfig::from_args(&args)?,
+        ..Default::default()
+    };
+
+    if let Some(path) = args.value::<PathBuf>("replay")? {
+        return replay::run(&path, state).await;
+    }
+
+    if let Some(admin_addr) = args.value::<SocketAddr>("admin")? {
+        info!("admin listener on {admin_addr}");
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = util::accept_loop(admin::handle_stream, admin_addr, state).await {
+                error!("admin listener: {e}");
+            }
+        });
+    }
+
+    util::accept_loop(handle_stream, args.addr()?, state).await
 }
 
 #[derive(Clone, Default)]
 struct State {
     cars: Arc<Mutex<CarsMap>>,
     dispatchers: Arc<RwLock<DispatchersMap>>,
+    cameras: Arc<Mutex<CamerasMap>>,
+    recorder: Option<Arc<Recorder>>,
+    plates: Arc<PlateRules>,
+    heartbeat: HeartbeatConfig,
 }
 
 type Map<K, V> = indexmap::IndexMap<K, V, ahash::RandomState>;
@@ -28,6 +77,14 @@ type Timestamp = u32;
 type Miles = u16;
 
 type CarsMap = Map<Plate, Car>;
+type CamerasMap = Map<SocketAddr, Camera>;
+
+#[derive(Debug, Clone, Copy)]
+struct Camera {
+    road: Road,
+    mile: Miles,
+    limit: u16,
+}
 
 #[derive(Default)]
 struct Car {
@@ -41,8 +98,11 @@ type Entries = BTreeMap<Timestamp, Miles>;
 type TicketsSet = indexmap::IndexSet<Day>;
 type Day = Timestamp;
 
+type TicketId = u32;
+
 #[derive(Debug)]
 struct Ticket {
+    id: TicketId,
     plate: Arc<Vec<u8>>,
     road: u16,
     mile1: u16,
@@ -53,7 +113,9 @@ struct Ticket {
 }
 
 impl Ticket {
+    #[allow(clippy::too_many_arguments)]
     fn new(
+        id: TicketId,
         plate: Arc<Vec<u8>>,
         road: u16,
         mut mile1: u16,
@@ -68,6 +130,7 @@ impl Ticket {
         }
 
         Self {
+            id,
             plate,
             road,
             mile1,
@@ -77,46 +140,67 @@ impl Ticket {
             speed: (speed * 100.0) as u16,
         }
     }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(18 + self.plate.len());
+        buf.push(TICKET);
+        buf.push(self.plate.len() as u8);
+        buf.extend_from_slice(&self.plate);
+        buf.extend_from_slice(&self.road.to_be_bytes());
+        buf.extend_from_slice(&self.mile1.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp1.to_be_bytes());
+        buf.extend_from_slice(&self.mile2.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp2.to_be_bytes());
+        buf.extend_from_slice(&self.speed.to_be_bytes());
+        buf
+    }
 }
 
-macro_rules! ticket_io_slices {
-    ($ticket:ident) => {
-        &[
-            IoSlice::new(&[TICKET]),
-            IoSlice::new(&[$ticket.plate.len() as u8]),
-            IoSlice::new(&$ticket.plate),
-            IoSlice::new(&$ticket.road.to_be_bytes()),
-            IoSlice::new(&$ticket.mile1.to_be_bytes()),
-            IoSlice::new(&$ticket.timestamp1.to_be_bytes()),
-            IoSlice::new(&$ticket.mile2.to_be_bytes()),
-            IoSlice::new(&$ticket.timestamp2.to_be_bytes()),
-            IoSlice::new(&$ticket.speed.to_be_bytes()),
-        ]
-    };
+impl fmt::Display for Ticket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ticket {} plate={} road={} mile1={} timestamp1={} mile2={} timestamp2={} speed={:.2}",
+            self.id,
+            String::from_utf8_lossy(&self.plate),
+            self.road,
+            self.mile1,
+            self.timestamp1,
+            self.mile2,
+            self.timestamp2,
+            self.speed as f64 / 100.0,
+        )
+    }
 }
 
 #[derive(Default)]
 struct DispatchersMap {
     roads_map: Map<Road, Dispatchers>,
-    dispatchers: Map<DispatchersId, mpsc::Sender<Ticket>>,
+    dispatchers: Map<DispatchersId, Dispatcher>,
     pending_tickets: Map<Road, Vec<Ticket>>,
     last_id: DispatchersId,
+    last_ticket_id: TicketId,
+    export: Option<Exporter>,
+}
+
+struct Dispatcher {
+    addr: SocketAddr,
+    roads: Vec<Road>,
+    tx: mpsc::Sender<Ticket>,
 }
 
 type Dispatchers = indexmap::IndexSet<DispatchersId>;
 
 struct DispatcherInsert {
-    pending_tickets: Vec<Ticket>,
+    id: DispatchersId,
     rx: mpsc::Receiver<Ticket>,
 }
 
 impl DispatchersMap {
-    fn insert(&mut self, roads: Vec<u16>) -> anyhow::Result<DispatcherInsert> {
+    fn insert(&mut self, addr: SocketAddr, roads: Vec<u16>) -> anyhow::Result<DispatcherInsert> {
         let dispatcher_id = self.last_id;
         self.last_id += 1;
 
-        let (tx, rx) = mpsc::channel(1024);
-
         for road in &roads {
             self.roads_map
                 .entry(*road)
@@ -124,20 +208,70 @@ impl DispatchersMap {
                 .insert(dispatcher_id);
         }
 
-        self.dispatchers.insert(dispatcher_id, tx);
-
-        let pending_tickets = roads
-            .into_iter()
-            .filter_map(|road| self.pending_tickets.remove(&road))
+        let pending_tickets: Vec<_> = roads
+            .iter()
+            .filter_map(|road| self.pending_tickets.remove(road))
             .flat_map(|v| v.into_iter())
             .collect();
 
+        // the pending tickets go through the channel like any other, so the ones the dispatcher
+        // never gets to write are requeued by `remove`, there is room for all of them
+        let (tx, rx) = mpsc::channel(1024 + pending_tickets.len());
+        for ticket in pending_tickets {
+            self.export(&ticket, Status::Dispatched);
+            if let Err(e) = tx.try_send(ticket) {
+                let ticket = e.into_inner();
+                self.export(&ticket, Status::Pending);
+                self.pending_tickets
+                    .entry(ticket.road)
+                    .or_default()
+                    .push(ticket);
+            }
+        }
+
+        self.dispatchers
+            .insert(dispatcher_id, Dispatcher { addr, roads, tx });
+
         Ok(DispatcherInsert {
+            id: dispatcher_id,
             rx,
-            pending_tickets,
         })
     }
 
+    /// Forget a disconnected dispatcher, so that tickets for its roads are kept pending instead of
+    /// being sent into a closed channel. Tickets it was sent but never got to write are handed to
+    /// another dispatcher for the road, or kept pending.
+    fn remove(&mut self, dispatcher_id: DispatchersId, mut rx: mpsc::Receiver<Ticket>) {
+        if let Some(dispatcher) = self.dispatchers.remove(&dispatcher_id) {
+            for road in &dispatcher.roads {
+                if let Some(dispatchers) = self.roads_map.get_mut(road) {
+                    dispatchers.remove(&dispatcher_id);
+                }
+            }
+        }
+
+        rx.close();
+        while let Ok(ticket) = rx.try_recv() {
+            let ticket = match self.dispatcher_for(ticket.road) {
+                Some(tx) => match tx.try_send(ticket) {
+                    Ok(()) => continue,
+                    Err(e) => e.into_inner(),
+                },
+                None => ticket,
+            };
+            warn!(
+                "requeuing ticket {} of a disconnected dispatcher",
+                ticket.id
+            );
+            self.export(&ticket, Status::Pending);
+            self.pending_tickets
+                .entry(ticket.road)
+                .or_default()
+                .push(ticket);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn send_ticket(
         &mut self,
         plate: Arc<Vec<u8>>,
@@ -147,54 +281,95 @@ impl DispatchersMap {
         mile2: u16,
         timestamp2: u32,
         speed: f64,
-    ) -> Result<(), mpsc::error::SendError<Ticket>> {
-        let ticket = Ticket::new(plate, road, mile1, timestamp1, mile2, timestamp2, speed);
+    ) {
+        let id = self.last_ticket_id;
+        self.last_ticket_id += 1;
 
-        if let Some(dispatcher_id) = self
-            .roads_map
-            .get(&road)
-            .and_then(|dispatchers| dispatchers.first())
-        {
-            if let Some(tx) = self.dispatchers.get(dispatcher_id) {
-                return tx.send(ticket).await;
+        let ticket = Ticket::new(id, plate, road, mile1, timestamp1, mile2, timestamp2, speed);
+
+        match self.dispatcher_for(road) {
+            Some(tx) => {
+                self.deliver(tx, ticket).await;
+            }
+            None => {
+                self.export(&ticket, Status::Pending);
+                self.pending_tickets.entry(road).or_default().push(ticket);
             }
         }
-
-        self.pending_tickets.entry(road).or_default().push(ticket);
-        Ok(())
     }
-}
 
-type DispatchersId = u16;
+    /// Hand `ticket` to a dispatcher, or keep it pending if the dispatcher went away in the
+    /// meantime. Returns whether it was handed over.
+    async fn deliver(&mut self, tx: mpsc::Sender<Ticket>, ticket: Ticket) -> bool {
+        match tx.reserve().await {
+            Ok(permit) => {
+                self.export(&ticket, Status::Dispatched);
+                permit.send(ticket);
+                true
+            }
+            Err(_) => {
+                self.export(&ticket, Status::Pending);
+                self.pending_tickets
+                    .entry(ticket.road)
+                    .or_default()
+                    .push(ticket);
+                false
+            }
+        }
+    }
 
-struct HeartbeatTimer {
-    next: time::Instant,
-    interval: Duration,
-}
-struct Heartbeat(Option<HeartbeatTimer>);
-
-impl Heartbeat {
-    fn from_interval(interval: u64) -> Self {
-        if interval != 0 {
-            let interval = Duration::from_millis(interval as u64 * 100);
-            Self(Some(HeartbeatTimer {
-                next: time::Instant::now() + interval,
-                interval,
-            }))
-        } else {
-            Self(None)
+    fn export(&self, ticket: &Ticket, status: Status) {
+        if let Some(export) = &self.export {
+            export.export(ticket, status);
         }
     }
 
-    async fn wait(&mut self) {
-        match &mut self.0 {
-            Some(timer) => {
-                time::sleep_until(timer.next).await;
-                timer.next = time::Instant::now() + timer.interval;
-            }
-            None => future::pending().await,
+    fn dispatcher_for(&self, road: Road) -> Option<mpsc::Sender<Ticket>> {
+        let dispatcher_id = self.roads_map.get(&road)?.first()?;
+        Some(self.dispatchers.get(dispatcher_id)?.tx.clone())
+    }
+
+    fn take_pending(&mut self, ticket_id: TicketId) -> Option<Ticket> {
+        self.pending_tickets.values_mut().find_map(|tickets| {
+            let idx = tickets.iter().position(|ticket| ticket.id == ticket_id)?;
+            Some(tickets.remove(idx))
+        })
+    }
+
+    /// Try to deliver a pending ticket again, e.g. after a dispatcher for its road has connected.
+    async fn reissue(&mut self, ticket_id: TicketId) -> anyhow::Result<()> {
+        let ticket = self
+            .take_pending(ticket_id)
+            .ok_or_else(|| anyhow!("no pending ticket with id {ticket_id}"))?;
+
+        let Some(tx) = self.dispatcher_for(ticket.road) else {
+            let road = ticket.road;
+            self.pending_tickets.entry(road).or_default().push(ticket);
+            return Err(anyhow!("no dispatcher connected for road {road}"));
+        };
+
+        if !self.deliver(tx, ticket).await {
+            return Err(anyhow!(
+                "dispatcher disconnected, the ticket is pending again"
+            ));
         }
+        Ok(())
     }
+
+    fn cancel(&mut self, ticket_id: TicketId) -> anyhow::Result<Ticket> {
+        let ticket = self
+            .take_pending(ticket_id)
+            .ok_or_else(|| anyhow!("no pending ticket with id {ticket_id}"))?;
+        self.export(&ticket, Status::Cancelled);
+        Ok(ticket)
+    }
+}
+
+type DispatchersId = u16;
+
+/// Clients ask for heartbeats in deciseconds.
+fn heartbeat_from_deciseconds(interval: u32, config: &HeartbeatConfig) -> Heartbeat {
+    Heartbeat::new(Duration::from_millis(interval as u64 * 100), config)
 }
 
 const ERROR: u8 = 0x10;
@@ -208,8 +383,15 @@ const HEARTBEAT: u8 = 0x41;
 const I_AM_CAMERA: u8 = 0x80;
 const I_AM_DISPATCHER: u8 = 0x81;
 
-async fn handle_stream(mut stream: TcpStream, state: State) -> anyhow::Result<()> {
-    let mut heartbeat = Heartbeat(None);
+async fn handle_stream(stream: TcpStream, state: State) -> anyhow::Result<()> {
+    let rec = Recording::new(&state.recorder);
+    let res = identify(stream, state, &rec).await;
+    rec.record(Event::Disconnect);
+    res
+}
+
+async fn identify(mut stream: TcpStream, state: State, rec: &Recording) -> anyhow::Result<()> {
+    let mut heartbeat = Heartbeat::disabled();
 
     loop {
         tokio::select! {
@@ -217,17 +399,18 @@ async fn handle_stream(mut stream: TcpStream, state: State) -> anyhow::Result<()
                 match msg_type_res? {
                     WANT_HEARTBEAT => {
                         let interval = stream.read_u32().await?;
-                        heartbeat = Heartbeat::from_interval(interval as u64);
+                        rec.record(Event::WantHeartbeat { interval });
+                        heartbeat = heartbeat_from_deciseconds(interval, &state.heartbeat);
                         continue;
                     }
 
-                    I_AM_CAMERA => return camera(stream, state, heartbeat).await,
-                    I_AM_DISPATCHER => return dispatcher(stream, state, heartbeat).await,
+                    I_AM_CAMERA => return camera(stream, state, heartbeat, rec).await,
+                    I_AM_DISPATCHER => return dispatcher(stream, state, heartbeat, rec).await,
 
-                    msg_type => return send_error(&mut stream, msg_type).await,
+                    msg_type => return send_invalid_msg_type(&mut stream, msg_type).await,
                 }
             },
-            _ = heartbeat.wait() => {
+            _ = heartbeat.tick() => {
                 stream.write_u8(HEARTBEAT).await?;
             }
         }
@@ -240,11 +423,37 @@ async fn handle_stream(mut stream: TcpStream, state: State) -> anyhow::Result<()
 async fn camera(
     mut stream: TcpStream,
     state: State,
+    heartbeat: Heartbeat,
+    rec: &Recording,
+) -> anyhow::Result<()> {
+    let addr = stream.peer_addr()?;
+    let camera = Camera {
+        road: stream.read_u16().await?,
+        mile: stream.read_u16().await?,
+        limit: stream.read_u16().await?,
+    };
+    rec.record(Event::IAmCamera {
+        road: camera.road,
+        mile: camera.mile,
+        limit: camera.limit,
+    });
+
+    state.cameras.lock().await.insert(addr, camera);
+    let res = camera_loop(&mut stream, &state, camera, heartbeat, rec).await;
+    state.cameras.lock().await.remove(&addr);
+
+    res
+}
+
+async fn camera_loop(
+    stream: &mut TcpStream,
+    state: &State,
+    camera: Camera,
     mut heartbeat: Heartbeat,
+    rec: &Recording,
 ) -> anyhow::Result<()> {
-    let road = stream.read_u16().await?;
-    let mile = stream.read_u16().await?;
-    let limit = stream.read_u16().await? as f64;
+    let Camera { road, mile, limit } = camera;
+    let limit = limit as f64;
 
     loop {
         tokio::select! {
@@ -252,13 +461,29 @@ async fn camera(
                 match msg_type_res? {
                     WANT_HEARTBEAT => {
                         let interval = stream.read_u32().await?;
-                        heartbeat = Heartbeat::from_interval(interval as u64);
+                        rec.record(Event::WantHeartbeat { interval });
+                        heartbeat = heartbeat_from_deciseconds(interval, &state.heartbeat);
                         continue;
                     }
 
                     PLATE => {
-                        let plate = read_str(&mut stream).await?;
+                        let plate = read_str(stream).await?;
                         let timestamp = stream.read_u32().await?;
+                        rec.record(Event::Plate {
+                            plate: plate.to_vec(),
+                            timestamp,
+                        });
+
+                        let plate = match state.plates.check(plate) {
+                            Ok(plate) => plate,
+                            Err(e) => match state.plates.on_reject {
+                                OnReject::Error => return send_error(stream, &e.to_string()).await,
+                                OnReject::Drop => {
+                                    warn!("dropping observation: {e}");
+                                    continue;
+                                }
+                            },
+                        };
 
                         let state = state.clone();
                         tokio::spawn(async move {
@@ -268,10 +493,10 @@ async fn camera(
                         });
                     }
 
-                    msg_type => return send_error(&mut stream, msg_type).await,
+                    msg_type => return send_invalid_msg_type(stream, msg_type).await,
                 }
             },
-            _ = heartbeat.wait() => {
+            _ = heartbeat.tick() => {
                 stream.write_u8(HEARTBEAT).await?;
             }
         }
@@ -346,6 +571,7 @@ async fn handle_plate(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn check_speed(
     plate: Plate,
     timestamp1: u32,
@@ -365,7 +591,7 @@ async fn check_speed(
             .write()
             .await
             .send_ticket(plate, road, mile1, timestamp1, mile2, timestamp2, speed)
-            .await?;
+            .await;
         return Ok(true);
     }
     Ok(false)
@@ -374,34 +600,52 @@ async fn check_speed(
 async fn dispatcher(
     mut stream: TcpStream,
     state: State,
-    mut heartbeat: Heartbeat,
+    heartbeat: Heartbeat,
+    rec: &Recording,
 ) -> anyhow::Result<()> {
     let numroads = stream.read_u8().await?;
     let mut roads = Vec::with_capacity(numroads as usize);
     for _ in 0..numroads {
         roads.push(stream.read_u16().await?);
     }
+    rec.record(Event::IAmDispatcher {
+        roads: roads.clone(),
+    });
+
+    let DispatcherInsert { id, mut rx } = state
+        .dispatchers
+        .write()
+        .await
+        .insert(stream.peer_addr()?, roads)?;
+
+    let res = dispatcher_loop(&mut stream, &mut rx, heartbeat, &state.heartbeat, rec).await;
+    // closed before waiting for the lock, which a ticket sent into the full channel could be
+    // holding: closing wakes it up with an error, so it gets kept pending instead
+    rx.close();
+    state.dispatchers.write().await.remove(id, rx);
+
+    res
+}
 
-    let DispatcherInsert {
-        pending_tickets,
-        mut rx,
-    } = state.dispatchers.write().await.insert(roads)?;
-
-    for ticket in pending_tickets {
-        stream.write_vectored(ticket_io_slices!(ticket)).await?;
-    }
-
+async fn dispatcher_loop(
+    stream: &mut TcpStream,
+    rx: &mut mpsc::Receiver<Ticket>,
+    mut heartbeat: Heartbeat,
+    config: &HeartbeatConfig,
+    rec: &Recording,
+) -> anyhow::Result<()> {
     loop {
         tokio::select! {
             msg_type_res = stream.read_u8() => {
                 match msg_type_res? {
                     WANT_HEARTBEAT => {
                         let interval = stream.read_u32().await?;
-                        heartbeat = Heartbeat::from_interval(interval as u64);
+                        rec.record(Event::WantHeartbeat { interval });
+                        heartbeat = heartbeat_from_deciseconds(interval, config);
                         continue;
                     }
 
-                    msg_type => return send_error(&mut stream, msg_type).await,
+                    msg_type => return send_invalid_msg_type(stream, msg_type).await,
                 }
             },
 
@@ -409,10 +653,10 @@ async fn dispatcher(
                 let Some(ticket) = msg_opt else {
                     break;
                 };
-                stream.write_vectored(ticket_io_slices!(ticket)).await?;
+                stream.write_all(&ticket.to_bytes()).await?;
             }
 
-            _ = heartbeat.wait() => {
+            _ = heartbeat.tick() => {
                 stream.write_u8(HEARTBEAT).await?;
             }
         }
@@ -422,11 +666,21 @@ async fn dispatcher(
     Ok(())
 }
 
-async fn send_error(stream: &mut TcpStream, msg_type: u8) -> anyhow::Result<()> {
-    stream
-        .write_vectored(&[IoSlice::new(&[ERROR]), IoSlice::new(&[0])])
-        .await?;
-    Err(anyhow!("invalid msg type: {msg_type}"))
+async fn send_invalid_msg_type(stream: &mut TcpStream, msg_type: u8) -> anyhow::Result<()> {
+    send_error(stream, &format!("invalid msg type: {msg_type}")).await
+}
+
+/// Send an Error message to the client, and return it as an error so the connection is closed.
+async fn send_error(stream: &mut TcpStream, msg: &str) -> anyhow::Result<()> {
+    let msg = &msg.as_bytes()[..msg.len().min(u8::MAX as usize)];
+
+    let mut buf = Vec::with_capacity(2 + msg.len());
+    buf.push(ERROR);
+    buf.push(msg.len() as u8);
+    buf.extend_from_slice(msg);
+    stream.write_all(&buf).await?;
+
+    Err(anyhow!("{}", String::from_utf8_lossy(msg)))
 }
 
 async fn read_str(stream: &mut TcpStream) -> anyhow::Result<Arc<Vec<u8>>> {
@@ -0,0 +1,249 @@
+//! Normalization and validation of plates reported by cameras.
+//!
+//! By default plates are used verbatim. The rules are configured with:
+//!
+//! - `--plate-fold-case`: uppercase ASCII letters
+//! - `--plate-strip`: remove whitespace and dashes
+//! - `--plate-charset=any|alnum|upper-alnum`: allowed characters, checked after normalization
+//! - `--plate-min-len=<n>`, `--plate-max-len=<n>`: allowed length, checked after normalization
+//! - `--plate-reject=error|drop`: either send an Error to the camera and disconnect it, or
+//!   silently drop the observation
+//!
+//! Rejected plates are counted either way, see the `stats` admin command.
+
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::anyhow;
+
+use crate::Plate;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Charset {
+    Any,
+    Alnum,
+    UpperAlnum,
+}
+
+impl Charset {
+    fn allows(self, b: u8) -> bool {
+        match self {
+            Charset::Any => true,
+            Charset::Alnum => b.is_ascii_alphanumeric(),
+            Charset::UpperAlnum => b.is_ascii_uppercase() || b.is_ascii_digit(),
+        }
+    }
+}
+
+impl FromStr for Charset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(Charset::Any),
+            "alnum" => Ok(Charset::Alnum),
+            "upper-alnum" => Ok(Charset::UpperAlnum),
+            s => Err(anyhow!(
+                "unknown charset {s:?}, expected any, alnum or upper-alnum"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OnReject {
+    Error,
+    Drop,
+}
+
+impl FromStr for OnReject {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(OnReject::Error),
+            "drop" => Ok(OnReject::Drop),
+            s => Err(anyhow!(
+                "unknown reject action {s:?}, expected error or drop"
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct PlateRules {
+    fold_case: bool,
+    strip: bool,
+    charset: Charset,
+    min_len: usize,
+    max_len: usize,
+    pub(crate) on_reject: OnReject,
+    rejected: AtomicU64,
+}
+
+impl Default for PlateRules {
+    fn default() -> Self {
+        Self {
+            fold_case: false,
+            strip: false,
+            charset: Charset::Any,
+            min_len: 0,
+            max_len: u8::MAX as usize,
+            on_reject: OnReject::Error,
+            rejected: AtomicU64::new(0),
+        }
+    }
+}
+
+impl PlateRules {
+    pub(crate) fn from_args(args: &util::Args) -> anyhow::Result<Self> {
+        let default = Self::default();
+
+        let rules = Self {
+            fold_case: args.flag("plate-fold-case"),
+            strip: args.flag("plate-strip"),
+            charset: args.value_or("plate-charset", default.charset)?,
+            min_len: args.value_or("plate-min-len", default.min_len)?,
+            max_len: args.value_or("plate-max-len", default.max_len)?,
+            on_reject: args.value_or("plate-reject", default.on_reject)?,
+            ..default
+        };
+
+        if rules.min_len > rules.max_len {
+            return Err(anyhow!("--plate-min-len is larger than --plate-max-len"));
+        }
+
+        Ok(rules)
+    }
+
+    /// Apply only the normalization rules, e.g. to look up a plate typed by an operator.
+    pub(crate) fn normalize(&self, plate: Plate) -> Plate {
+        if !self.fold_case && !self.strip {
+            return plate;
+        }
+
+        let normalized = plate
+            .iter()
+            .filter(|b| !self.strip || !(b.is_ascii_whitespace() || **b == b'-'))
+            .map(|b| {
+                if self.fold_case {
+                    b.to_ascii_uppercase()
+                } else {
+                    *b
+                }
+            })
+            .collect();
+
+        Arc::new(normalized)
+    }
+
+    /// Normalize and validate a plate as received from a camera.
+    pub(crate) fn check(&self, plate: Plate) -> anyhow::Result<Plate> {
+        let plate = self.normalize(plate);
+
+        let res = if plate.len() < self.min_len || plate.len() > self.max_len {
+            Err(anyhow!(
+                "invalid plate length {}, expected {} to {}",
+                plate.len(),
+                self.min_len,
+                self.max_len
+            ))
+        } else if let Some(b) = plate.iter().find(|b| !self.charset.allows(**b)) {
+            Err(anyhow!("invalid character {:?} in plate", *b as char))
+        } else {
+            Ok(plate)
+        };
+
+        if res.is_err() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+
+        res
+    }
+
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plate(s: &str) -> Plate {
+        Arc::new(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn default_rules_keep_plates_verbatim() {
+        let rules = PlateRules::default();
+        assert_eq!(rules.check(plate("ab-12 x")).unwrap(), plate("ab-12 x"));
+        assert_eq!(rules.rejected(), 0);
+    }
+
+    #[test]
+    fn normalize_folds_case_and_strips() {
+        let rules = PlateRules {
+            fold_case: true,
+            strip: true,
+            ..Default::default()
+        };
+        assert_eq!(rules.normalize(plate("ab-12 x\t")), plate("AB12X"));
+
+        let fold_only = PlateRules {
+            fold_case: true,
+            ..Default::default()
+        };
+        assert_eq!(fold_only.normalize(plate("ab-1")), plate("AB-1"));
+    }
+
+    #[test]
+    fn charset_is_checked_after_normalization() {
+        let rules = PlateRules {
+            fold_case: true,
+            strip: true,
+            charset: Charset::UpperAlnum,
+            ..Default::default()
+        };
+        assert_eq!(rules.check(plate("un-1x")).unwrap(), plate("UN1X"));
+        assert!(rules.check(plate("A!")).is_err());
+
+        let alnum = PlateRules {
+            charset: Charset::Alnum,
+            ..Default::default()
+        };
+        assert!(alnum.check(plate("ab12")).is_ok());
+        assert!(alnum.check(plate("ab 12")).is_err());
+        assert!(alnum.check(Arc::new(vec![0xff, b'A'])).is_err());
+    }
+
+    #[test]
+    fn length_bounds_are_inclusive() {
+        let rules = PlateRules {
+            min_len: 2,
+            max_len: 4,
+            ..Default::default()
+        };
+        assert!(rules.check(plate("A")).is_err());
+        assert!(rules.check(plate("AB")).is_ok());
+        assert!(rules.check(plate("ABCD")).is_ok());
+        assert!(rules.check(plate("ABCDE")).is_err());
+        assert_eq!(rules.rejected(), 2);
+    }
+
+    #[test]
+    fn parses_options() {
+        assert_eq!(
+            "upper-alnum".parse::<Charset>().unwrap(),
+            Charset::UpperAlnum
+        );
+        assert!("lower".parse::<Charset>().is_err());
+        assert_eq!("drop".parse::<OnReject>().unwrap(), OnReject::Drop);
+        assert!("ignore".parse::<OnReject>().is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Recording inbound messages with `--record=<path>`, and replaying them offline with
+//! `--replay=<path>`.
+//!
+//! A recording is a JSON Lines file with one entry per inbound message, tagged with the
+//! connection it arrived on and the milliseconds elapsed since the server started. Plates are
+//! recorded as arrays of bytes, as they needn't be UTF-8. Entries are written by a blocking thread
+//! fed through a channel, so recording never makes a connection wait for the disk. Replaying feeds
+//! the entries, in order, through the same `handle_plate` and `DispatchersMap` logic as the live
+//! server, but without sockets or wall clock time: the recorded arrival time is used as the clock,
+//! and every ticket a dispatcher would have received is printed to stdout, so two runs can be
+//! diffed.
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, time::Instant};
+use tracing::error;
+
+use crate::{handle_plate, Camera, DispatcherInsert, DispatchersId, Road, State, Ticket};
+
+type ConnId = u64;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Event {
+    WantHeartbeat { interval: u32 },
+    IAmCamera { road: u16, mile: u16, limit: u16 },
+    IAmDispatcher { roads: Vec<Road> },
+    Plate { plate: Vec<u8>, timestamp: u32 },
+    Disconnect,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    at_ms: u64,
+    conn: ConnId,
+    #[serde(flatten)]
+    event: Event,
+}
+
+pub(crate) struct Recorder {
+    start: Instant,
+    last_conn: AtomicU64,
+    tx: mpsc::UnboundedSender<Entry>,
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || {
+            let mut writer = BufWriter::new(file);
+            while let Some(entry) = rx.blocking_recv() {
+                if let Err(e) = write_entry(&mut writer, &entry) {
+                    error!("failed to record message: {e}");
+                }
+                // flush once caught up, rather than after every entry of a burst
+                if rx.is_empty() {
+                    if let Err(e) = writer.flush() {
+                        error!("failed to record message: {e}");
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            start: Instant::now(),
+            last_conn: AtomicU64::new(0),
+            tx,
+        })
+    }
+
+    fn write(&self, conn: ConnId, event: Event) {
+        let entry = Entry {
+            at_ms: self.start.elapsed().as_millis() as u64,
+            conn,
+            event,
+        };
+        // the writer only stops once the recorder is dropped
+        let _ = self.tx.send(entry);
+    }
+}
+
+fn write_entry(writer: &mut impl Write, entry: &Entry) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, entry)?;
+    writer.write_all(b"\n")
+}
+
+/// Per connection handle to the recorder, which does nothing when recording is disabled.
+pub(crate) struct Recording(Option<(ConnId, Arc<Recorder>)>);
+
+impl Recording {
+    pub(crate) fn new(recorder: &Option<Arc<Recorder>>) -> Self {
+        Self(recorder.as_ref().map(|recorder| {
+            let conn = recorder.last_conn.fetch_add(1, Ordering::Relaxed);
+            (conn, recorder.clone())
+        }))
+    }
+
+    pub(crate) fn record(&self, event: Event) {
+        if let Some((conn, recorder)) = &self.0 {
+            recorder.write(*conn, event);
+        }
+    }
+}
+
+enum Conn {
+    Camera(Camera),
+    Dispatcher {
+        id: DispatchersId,
+        rx: mpsc::Receiver<Ticket>,
+    },
+}
+
+pub(crate) async fn run(path: &Path, state: State) -> anyhow::Result<()> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        entries.push(serde_json::from_str::<Entry>(&line?)?);
+    }
+    // the recorder serializes writes but not the timestamps taken right before them
+    entries.sort_by_key(|entry| entry.at_ms);
+
+    let stdout = &mut io::stdout().lock();
+    let mut conns = BTreeMap::new();
+
+    for Entry { at_ms, conn, event } in entries {
+        match event {
+            Event::WantHeartbeat { .. } => {}
+
+            Event::IAmCamera { road, mile, limit } => {
+                conns.insert(conn, Conn::Camera(Camera { road, mile, limit }));
+            }
+
+            Event::IAmDispatcher { roads } => {
+                // replayed dispatchers have no socket, so give them a recognizable fake address
+                let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, conn as u16));
+                // its pending tickets are printed with the rest of its channel below
+                let DispatcherInsert { id, rx } =
+                    state.dispatchers.write().await.insert(addr, roads)?;
+                conns.insert(conn, Conn::Dispatcher { id, rx });
+            }
+
+            Event::Plate { plate, timestamp } => {
+                let Some(Conn::Camera(camera)) = conns.get(&conn) else {
+                    error!("plate from conn {conn}, which is not a camera");
+                    continue;
+                };
+                let plate = match state.plates.check(Arc::new(plate)) {
+                    Ok(plate) => plate,
+                    Err(e) => {
+                        writeln!(stdout, "{at_ms} conn={conn} rejected: {e}")?;
+                        continue;
+                    }
+                };
+                handle_plate(
+                    plate,
+                    timestamp,
+                    camera.road,
+                    camera.mile,
+                    camera.limit as f64,
+                    state.clone(),
+                )
+                .await?;
+            }
+
+            Event::Disconnect => {
+                if let Some(Conn::Dispatcher { id, rx }) = conns.remove(&conn) {
+                    state.dispatchers.write().await.remove(id, rx);
+                }
+            }
+        }
+
+        for (conn, dispatcher) in conns.iter_mut() {
+            let Conn::Dispatcher { rx, .. } = dispatcher else {
+                continue;
+            };
+            while let Ok(ticket) = rx.try_recv() {
+                writeln!(stdout, "{at_ms} conn={conn} {ticket}")?;
+            }
+        }
+    }
+
+    for tickets in state.dispatchers.read().await.pending_tickets.values() {
+        for ticket in tickets {
+            writeln!(stdout, "end pending {ticket}")?;
+        }
+    }
+
+    Ok(())
+}
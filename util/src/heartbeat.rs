@@ -0,0 +1,273 @@
+//! Periodic heartbeats for binary protocols, where the client asks for an interval and the server
+//! has to keep sending beats at that rate for the rest of the connection.
+//!
+//! Beats are scheduled from the time the heartbeat was created rather than from when the previous
+//! beat was sent, so they do not drift. When a beat is missed, e.g. because the connection was busy
+//! writing something else, the configured [`MissedTickBehavior`] decides whether to catch up.
+
+use std::{
+    future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+
+use crate::Args;
+
+/// Settings shared by all heartbeats of a server, configured with:
+///
+/// - `--heartbeat-min-ms=<n>`, `--heartbeat-max-ms=<n>`: clamp the interval asked by clients
+/// - `--heartbeat-missed=burst|delay|skip`: what to do about missed beats, defaults to `skip`
+/// - `--heartbeat-late-ms=<n>`: how late a beat has to be to count as late, defaults to 10
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    pub missed_tick_behavior: MissedTickBehavior,
+    pub late_after: Duration,
+    pub metrics: Arc<HeartbeatMetrics>,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::ZERO,
+            max_interval: Duration::MAX,
+            missed_tick_behavior: MissedTickBehavior::Skip,
+            late_after: Duration::from_millis(10),
+            metrics: Default::default(),
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    pub fn from_args(args: &Args) -> anyhow::Result<Self> {
+        let default = Self::default();
+
+        let missed_tick_behavior = match args.value::<String>("heartbeat-missed")?.as_deref() {
+            None => default.missed_tick_behavior,
+            Some("burst") => MissedTickBehavior::Burst,
+            Some("delay") => MissedTickBehavior::Delay,
+            Some("skip") => MissedTickBehavior::Skip,
+            Some(s) => {
+                return Err(anyhow!(
+                    "unknown --heartbeat-missed {s:?}, expected burst, delay or skip"
+                ))
+            }
+        };
+
+        let config = Self {
+            min_interval: args
+                .value("heartbeat-min-ms")?
+                .map_or(default.min_interval, Duration::from_millis),
+            max_interval: args
+                .value("heartbeat-max-ms")?
+                .map_or(default.max_interval, Duration::from_millis),
+            missed_tick_behavior,
+            late_after: args
+                .value("heartbeat-late-ms")?
+                .map_or(default.late_after, Duration::from_millis),
+            ..default
+        };
+
+        if config.min_interval > config.max_interval {
+            return Err(anyhow!(
+                "--heartbeat-min-ms is larger than --heartbeat-max-ms"
+            ));
+        }
+
+        Ok(config)
+    }
+}
+
+/// Counters aggregated over every heartbeat created from the same [`HeartbeatConfig`].
+#[derive(Debug, Default)]
+pub struct HeartbeatMetrics {
+    beats: AtomicU64,
+    late_beats: AtomicU64,
+    total_lateness_us: AtomicU64,
+    max_lateness_us: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatMetricsSnapshot {
+    pub beats: u64,
+    pub late_beats: u64,
+    pub total_lateness: Duration,
+    pub max_lateness: Duration,
+}
+
+impl HeartbeatMetrics {
+    fn record(&self, lateness: Duration, late_after: Duration) {
+        self.beats.fetch_add(1, Ordering::Relaxed);
+
+        if lateness <= late_after {
+            return;
+        }
+
+        let lateness_us = lateness.as_micros() as u64;
+        self.late_beats.fetch_add(1, Ordering::Relaxed);
+        self.total_lateness_us
+            .fetch_add(lateness_us, Ordering::Relaxed);
+        self.max_lateness_us
+            .fetch_max(lateness_us, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HeartbeatMetricsSnapshot {
+        HeartbeatMetricsSnapshot {
+            beats: self.beats.load(Ordering::Relaxed),
+            late_beats: self.late_beats.load(Ordering::Relaxed),
+            total_lateness: Duration::from_micros(self.total_lateness_us.load(Ordering::Relaxed)),
+            max_lateness: Duration::from_micros(self.max_lateness_us.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+struct HeartbeatTimer {
+    interval: Interval,
+    late_after: Duration,
+    metrics: Arc<HeartbeatMetrics>,
+}
+
+pub struct Heartbeat(Option<HeartbeatTimer>);
+
+impl Heartbeat {
+    /// A heartbeat that never beats, for connections which have not asked for one.
+    pub fn disabled() -> Self {
+        Self(None)
+    }
+
+    /// A zero `interval` disables the heartbeat, anything else is clamped to the configured range.
+    pub fn new(interval: Duration, config: &HeartbeatConfig) -> Self {
+        if interval.is_zero() {
+            return Self::disabled();
+        }
+
+        let interval = interval.clamp(config.min_interval, config.max_interval);
+        // a configured maximum of zero would still give a zero interval, which tokio rejects
+        let interval = interval.max(Duration::from_millis(1));
+
+        let mut timer = time::interval_at(Instant::now() + interval, interval);
+        timer.set_missed_tick_behavior(config.missed_tick_behavior);
+
+        Self(Some(HeartbeatTimer {
+            interval: timer,
+            late_after: config.late_after,
+            metrics: config.metrics.clone(),
+        }))
+    }
+
+    /// Wait for the next beat. This is cancel safe, so it can be used in a `tokio::select!` loop.
+    pub async fn tick(&mut self) {
+        match &mut self.0 {
+            Some(timer) => {
+                let deadline = timer.interval.tick().await;
+                timer
+                    .metrics
+                    .record(Instant::now() - deadline, timer.late_after);
+            }
+            None => future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn period(heartbeat: &Heartbeat) -> Option<Duration> {
+        heartbeat.0.as_ref().map(|timer| timer.interval.period())
+    }
+
+    #[tokio::test]
+    async fn zero_interval_disables() {
+        let heartbeat = Heartbeat::new(Duration::ZERO, &HeartbeatConfig::default());
+        assert_eq!(period(&heartbeat), None);
+    }
+
+    #[tokio::test]
+    async fn interval_is_clamped() {
+        let config = HeartbeatConfig {
+            min_interval: Duration::from_millis(250),
+            max_interval: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let period_for = |ms| period(&Heartbeat::new(Duration::from_millis(ms), &config));
+
+        assert_eq!(period_for(100), Some(Duration::from_millis(250)));
+        assert_eq!(period_for(1000), Some(Duration::from_millis(1000)));
+        assert_eq!(period_for(60_000), Some(Duration::from_secs(10)));
+    }
+
+    #[tokio::test]
+    async fn zero_max_interval_still_beats() {
+        let config = HeartbeatConfig {
+            max_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        let heartbeat = Heartbeat::new(Duration::from_secs(1), &config);
+        assert_eq!(period(&heartbeat), Some(Duration::from_millis(1)));
+    }
+
+    #[tokio::test]
+    async fn ticks_are_counted() {
+        let config = HeartbeatConfig::default();
+        let mut heartbeat = Heartbeat::new(Duration::from_millis(5), &config);
+        heartbeat.tick().await;
+        heartbeat.tick().await;
+        assert_eq!(config.metrics.snapshot().beats, 2);
+    }
+
+    #[test]
+    fn lateness_is_recorded_past_the_threshold() {
+        let metrics = HeartbeatMetrics::default();
+        let late_after = Duration::from_millis(10);
+        metrics.record(Duration::from_millis(5), late_after);
+        metrics.record(Duration::from_millis(30), late_after);
+        metrics.record(Duration::from_millis(20), late_after);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.beats, 3);
+        assert_eq!(snapshot.late_beats, 2);
+        assert_eq!(snapshot.total_lateness, Duration::from_millis(50));
+        assert_eq!(snapshot.max_lateness, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn config_from_args() {
+        let config = HeartbeatConfig::from_args(&args(&[
+            "--heartbeat-min-ms=100",
+            "--heartbeat-max-ms=2000",
+            "--heartbeat-missed=burst",
+            "--heartbeat-late-ms=5",
+        ]))
+        .unwrap();
+        assert_eq!(config.min_interval, Duration::from_millis(100));
+        assert_eq!(config.max_interval, Duration::from_millis(2000));
+        assert_eq!(config.missed_tick_behavior, MissedTickBehavior::Burst);
+        assert_eq!(config.late_after, Duration::from_millis(5));
+
+        let default = HeartbeatConfig::from_args(&args(&[])).unwrap();
+        assert_eq!(default.missed_tick_behavior, MissedTickBehavior::Skip);
+        assert_eq!(default.max_interval, Duration::MAX);
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        assert!(HeartbeatConfig::from_args(&args(&["--heartbeat-missed=never"])).is_err());
+        assert!(HeartbeatConfig::from_args(&args(&[
+            "--heartbeat-min-ms=200",
+            "--heartbeat-max-ms=100",
+        ]))
+        .is_err());
+    }
+}
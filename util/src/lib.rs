@@ -1,4 +1,6 @@
-use std::{env, future::Future, net::SocketAddr};
+pub mod heartbeat;
+
+use std::{collections::HashMap, env, future::Future, net::SocketAddr, str::FromStr};
 
 use anyhow::anyhow;
 use tokio::net::{TcpListener, TcpStream};
@@ -28,9 +30,81 @@ pub fn addr_from_args() -> anyhow::Result<SocketAddr> {
         .map_err(Into::into)
 }
 
+/// Command line arguments of the form `<positional>... [--flag] [--key=value]...`.
+#[derive(Debug, Default, Clone)]
+pub struct Args {
+    positional: Vec<String>,
+    flags: HashMap<String, Option<String>>,
+}
+
+impl Args {
+    pub fn from_env() -> Self {
+        Self::parse(env::args().skip(1))
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Self {
+        let mut res = Self::default();
+
+        for arg in args {
+            let Some(flag) = arg.strip_prefix("--") else {
+                res.positional.push(arg);
+                continue;
+            };
+
+            let (key, value) = match flag.split_once('=') {
+                Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                None => (flag.to_string(), None),
+            };
+            res.flags.insert(key, value);
+        }
+
+        res
+    }
+
+    pub fn positional(&self, idx: usize) -> Option<&str> {
+        self.positional.get(idx).map(String::as_str)
+    }
+
+    /// The first positional argument, which every binary in this repo uses as its listen address.
+    pub fn addr(&self) -> anyhow::Result<SocketAddr> {
+        self.positional(0)
+            .ok_or_else(|| anyhow!("no addr provided in arguments"))?
+            .parse()
+            .map_err(Into::into)
+    }
+
+    pub fn flag(&self, key: &str) -> bool {
+        self.flags.contains_key(key)
+    }
+
+    pub fn value<T>(&self, key: &str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        match self.flags.get(key) {
+            None => Ok(None),
+            Some(None) => Err(anyhow!("--{key} expects a value, use --{key}=<value>")),
+            Some(Some(value)) => value
+                .parse()
+                .map(Some)
+                .map_err(|e| anyhow!("invalid value for --{key}: {e}")),
+        }
+    }
+
+    pub fn value_or<T>(&self, key: &str, default: T) -> anyhow::Result<T>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        Ok(self.value(key)?.unwrap_or(default))
+    }
+}
+
 pub fn init_tracing() {
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
         .init()
 }
 